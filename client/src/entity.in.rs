@@ -5,19 +5,21 @@ struct Test {
     str_val: String,
 
     #[refer(test_id, test_id)]
-    #[cascade(insert)]
+    #[cascade(insert, update, delete)]
+    #[fetch(eager)]
     test: Test,
 
     #[pointer]
-    #[cascade(insert)]
+    #[cascade(insert, update, delete)]
+    #[fetch(eager)]
     ptr: Ptr,
 
     #[one_one]
-    #[cascade(insert)]
+    #[cascade(insert, update, delete)]
     oo: Oo,
 
     #[one_many]
-    #[cascade(insert)]
+    #[cascade(insert, update, delete)]
     om: Om,
 }
 
@@ -2,6 +2,8 @@ use orm::Entity;
 use orm::EntityMeta;
 use orm::Insert;
 use orm::Select;
+use orm::Order;
+use orm::Projection;
 use orm::Execute;
 use orm::Cond;
 use orm::JoinCond;
@@ -11,7 +13,9 @@ use orm;
 use std::cell::RefCell;
 use std::rc::Rc;
 use std::mem;
+use std;
 
+use mysql;
 use mysql::Value;
 use entity::*;
 
@@ -19,6 +23,15 @@ fn open_db() -> Db {
     orm::open("root", "root", "172.16.16.224", 3306, "test", orm_meta()).unwrap()
 }
 
+// insert只级联显式with的关系
+fn insert_refers() -> Execute {
+    let mut insert = Execute::insert();
+    insert.with("ptr");
+    insert.with("oo");
+    insert.with("om");
+    insert
+}
+
 #[test]
 fn test() {
     insert_test();
@@ -117,7 +130,9 @@ pub fn insert_select_test() {
     assert!(t.get_oo().get_id() == 1);
     assert!(res == 5);
 
+    // 只加载显式with的关系
     let mut select = Select::<Test>::new();
+    select.no_eager();
     select.wher(&Cond::by_id(t.get_id()));
     select.with("ptr");
     select.with("oo");
@@ -133,6 +148,7 @@ pub fn insert_select_test() {
     t.debug();
 }
 
+#[allow(deprecated)]
 pub fn join_test() {
     let db = open_db();
     db.rebuild();
@@ -154,7 +170,9 @@ pub fn join_test() {
     insert.with("om");
     let res = insert.execute(&mut db.get_conn(), &t).unwrap();
 
+    // 只加载显式with的关系
     let mut select = Select::<Test>::new();
+    select.no_eager();
     select.wher(&Cond::by_id(t.get_id()));
     select.with("ptr");
     select.with("oo");
@@ -182,6 +200,1149 @@ pub fn join_test() {
 }
 
 
+pub fn update_test() {
+    let db = open_db();
+    db.rebuild();
+
+    let mut t = Test::default();
+    t.set_int_val(100);
+    t.set_str_val("hello world");
+    t.set_ptr(&Ptr::default());
+    t.get_ptr().set_int_val(200);
+    t.set_oo(&Oo::default());
+    t.get_oo().set_int_val(300);
+    t.set_om(vec![Om::default(), Om::default()]);
+    t.get_om().get_mut(0).unwrap().set_int_val(400);
+    t.get_om().get_mut(1).unwrap().set_int_val(500);
+
+    let insert = insert_refers();
+    let res = insert.execute(&mut db.get_conn(), &t).unwrap();
+    assert!(res == 5);
+
+    t.set_int_val(101);
+    t.get_ptr().set_int_val(201);
+    t.get_oo().set_int_val(301);
+    t.get_om().get_mut(0).unwrap().set_int_val(401);
+    let update = Execute::update();
+    let res = update.execute(&mut db.get_conn(), &t).unwrap();
+    assert!(res == 4);
+
+    let mut select = Select::<Test>::new();
+    select.wher(&Cond::by_id(t.get_id()));
+    select.with("ptr");
+    select.with("oo");
+    select.with("om");
+    let t = select.query(&mut db.get_conn()).unwrap().remove(0);
+    assert!(t.get_int_val() == 101);
+    assert!(t.get_str_val() == "hello world");
+    assert!(t.get_ptr().get_int_val() == 201);
+    assert!(t.get_oo().get_int_val() == 301);
+    assert!(t.get_om()[0].get_int_val() == 401);
+    assert!(t.get_om()[1].get_int_val() == 500);
+
+    // 没有id的对象不能更新
+    let t = Test::default();
+    assert!(Execute::update().execute(&mut db.get_conn(), &t).is_err());
+}
+
+
+pub fn delete_test() {
+    let db = open_db();
+    db.rebuild();
+
+    let mut t = Test::default();
+    t.set_int_val(100);
+    t.set_str_val("hello world");
+    t.set_ptr(&Ptr::default());
+    t.get_ptr().set_int_val(200);
+    t.set_oo(&Oo::default());
+    t.get_oo().set_int_val(300);
+    t.set_om(vec![Om::default(), Om::default()]);
+    t.get_om().get_mut(0).unwrap().set_int_val(400);
+    t.get_om().get_mut(1).unwrap().set_int_val(500);
+
+    let insert = insert_refers();
+    let res = insert.execute(&mut db.get_conn(), &t).unwrap();
+    assert!(res == 5);
+
+    let delete = Execute::delete();
+    let res = delete.execute(&mut db.get_conn(), &t).unwrap();
+    assert!(res == 5);
+
+    let select = Select::<Test>::new();
+    assert!(select.query(&mut db.get_conn()).unwrap().len() == 0);
+    let select = Select::<Ptr>::new();
+    assert!(select.query(&mut db.get_conn()).unwrap().len() == 0);
+    let select = Select::<Oo>::new();
+    assert!(select.query(&mut db.get_conn()).unwrap().len() == 0);
+    let select = Select::<Om>::new();
+    assert!(select.query(&mut db.get_conn()).unwrap().len() == 0);
+    // 删除之后id被清空
+    assert!(t.do_inner(|inner| inner.get_id_u64().is_none()));
+    assert!(t.get_om()[0].do_inner(|inner| inner.get_id_u64().is_none()));
+
+    // 没有加载的one_one/one_many按外键删除
+    let mut t = Test::default();
+    t.set_int_val(100);
+    t.set_str_val("hello world");
+    t.set_ptr(&Ptr::default());
+    t.set_oo(&Oo::default());
+    t.set_om(vec![Om::default(), Om::default()]);
+    db.execute(&insert_refers(), &t).unwrap();
+    let mut select = Select::<Test>::new();
+    select.no_eager();
+    let t = db.query(&select).unwrap().remove(0);
+    assert!(!t.is_loaded("oo") && !t.is_loaded("om"));
+    assert!(Execute::delete().execute(&mut db.get_conn(), &t).unwrap() == 4);
+    assert!(db.query(&Select::<Test>::new()).unwrap().len() == 0);
+    assert!(db.query(&Select::<Oo>::new()).unwrap().len() == 0);
+    assert!(db.query(&Select::<Om>::new()).unwrap().len() == 0);
+    assert!(db.query(&Select::<Ptr>::new()).unwrap().len() == 1);
+
+    // 没有id的对象不能删除
+    let t = Test::default();
+    assert!(Execute::delete().execute(&mut db.get_conn(), &t).is_err());
+}
+
+
+pub fn dirty_test() {
+    let db = open_db();
+    db.rebuild();
+
+    let mut t = Test::default();
+    t.set_int_val(100);
+    t.set_str_val("hello world");
+    db.insert(&t).unwrap();
+    assert!(!t.do_inner(|inner| inner.is_dirty()));
+
+    let mut select = Select::<Test>::new();
+    select.wher(&Cond::by_id(t.get_id()));
+    let mut t = select.query(&mut db.get_conn()).unwrap().remove(0);
+    assert!(!t.do_inner(|inner| inner.is_dirty()));
+
+    t.set_int_val(200);
+    t.set_str_val("hello world");
+    assert!(t.do_inner(|inner| inner.dirty_fields()) == vec!["int_val".to_string()]);
+    t.set_int_val(100);
+    assert!(!t.do_inner(|inner| inner.is_dirty()));
+
+    t.set_int_val(300);
+    let res = Execute::update().execute(&mut db.get_conn(), &t).unwrap();
+    assert!(res == 1);
+    assert!(!t.do_inner(|inner| inner.is_dirty()));
+
+    // one_one的外键在oo上, 设置oo不会改动t的id
+    let id = t.get_id();
+    let oo = Oo::default();
+    t.set_oo(&oo);
+    assert!(t.get_id() == id);
+    assert!(oo.get_test_id() == id);
+    assert!(!t.do_inner(|inner| inner.is_dirty()));
+}
+
+
+pub fn one_one_test() {
+    let db = open_db();
+    db.rebuild();
+
+    let mut t = Test::default();
+    t.set_int_val(100);
+    t.set_str_val("hello world");
+    t.set_oo(&Oo::default());
+    t.get_oo().set_int_val(200);
+    db.execute(&insert_refers(), &t).unwrap();
+    let id = t.get_id();
+    assert!(t.get_oo().get_test_id() == id);
+
+    // 查出来之后换一个oo, 外键写在新的oo上, t的id不变, 原来的oo解除关系
+    let mut select = Select::<Test>::new();
+    select.wher(&Cond::by_id(id));
+    select.with("oo");
+    let t = db.query(&select).unwrap().remove(0);
+    let old = t.get_oo();
+    assert!(old.get_int_val() == 200);
+    let mut oo = Oo::default();
+    oo.set_int_val(300);
+    t.set_oo(&oo);
+    assert!(t.get_id() == id);
+    assert!(oo.get_test_id() == id);
+    assert!(old.is_test_id_null());
+    assert!(!t.do_inner(|inner| inner.is_dirty()));
+
+    // 保存之后重新查询, 得到的是新的oo
+    assert!(Execute::update().execute(&mut db.get_conn(), &old).unwrap() == 1);
+    db.insert(&oo).unwrap();
+    let t = db.query(&select).unwrap().remove(0);
+    assert!(t.get_id() == id);
+    assert!(t.get_oo().get_id() == oo.get_id());
+    assert!(t.get_oo().get_int_val() == 300);
+    assert!(db.query(&Select::<Oo>::new()).unwrap().len() == 2);
+}
+
+
+pub fn transaction_test() {
+    let db = open_db();
+    db.rebuild();
+
+    let res = db.transaction(|tx| {
+        let mut t = Test::default();
+        t.set_int_val(100);
+        t.set_str_val("hello world");
+        t.set_ptr(&Ptr::default());
+        t.get_ptr().set_int_val(200);
+        try!(tx.insert(&t));
+        assert!(tx.query(&Select::<Test>::new()).unwrap().len() == 1);
+        assert!(tx.page(&Select::<Test>::new(), 1, 10).unwrap().total == 1);
+        let err = std::io::Error::new(std::io::ErrorKind::Other, "rollback");
+        Err::<(), _>(mysql::Error::from(err))
+    });
+    assert!(res.is_err());
+    assert!(db.query(&Select::<Test>::new()).unwrap().len() == 0);
+    assert!(db.query(&Select::<Ptr>::new()).unwrap().len() == 0);
+
+    let res = db.transaction(|tx| {
+        let mut t = Test::default();
+        t.set_int_val(100);
+        t.set_str_val("hello world");
+        t.set_ptr(&Ptr::default());
+        t.get_ptr().set_int_val(200);
+        tx.execute(&insert_refers(), &t)
+    });
+    assert!(res.unwrap() == 2);
+    assert!(db.query(&Select::<Test>::new()).unwrap().len() == 1);
+    assert!(db.query(&Select::<Ptr>::new()).unwrap().len() == 1);
+}
+
+
+pub fn nested_transaction_test() {
+    let db = open_db();
+    db.rebuild();
+
+    let res = db.transaction(|tx| {
+        let mut t = Test::default();
+        t.set_int_val(100);
+        t.set_str_val("hello world");
+        try!(tx.insert(&t));
+
+        let res = tx.transaction(|tx| {
+            let mut t = Test::default();
+            t.set_int_val(200);
+            t.set_str_val("hello world");
+            t.set_ptr(&Ptr::default());
+            t.get_ptr().set_int_val(300);
+            try!(tx.insert(&t));
+            let err = std::io::Error::new(std::io::ErrorKind::Other, "rollback");
+            Err::<(), _>(mysql::Error::from(err))
+        });
+        assert!(res.is_err());
+
+        tx.transaction(|tx| {
+            let mut t = Test::default();
+            t.set_int_val(400);
+            t.set_str_val("hello world");
+            tx.insert(&t)
+        })
+    });
+    assert!(res.unwrap() == 1);
+    let vec = db.query(&Select::<Test>::new()).unwrap();
+    assert!(vec.len() == 2);
+    assert!(vec[0].get_int_val() == 100);
+    assert!(vec[1].get_int_val() == 400);
+    assert!(db.query(&Select::<Ptr>::new()).unwrap().len() == 0);
+}
+
+
+pub fn session_test() {
+    let db = open_db();
+    db.rebuild();
+
+    let session = db.open_session();
+    let mut t = Test::default();
+    t.set_int_val(100);
+    t.set_str_val("hello world");
+    let mut ptr = Ptr::default();
+    ptr.set_int_val(200);
+    t.set_ptr(&ptr);
+    // 先插入t再插入ptr，flush时按依赖顺序执行
+    session.insert(&t).unwrap();
+    session.insert(&ptr).unwrap();
+    assert!(session.flush().unwrap() == 2);
+    assert!(t.get_ptr_id() == ptr.get_id());
+
+    let id = t.get_id();
+    let t1 = session.get::<Test>(id).unwrap().unwrap();
+    let t2 = session.query(&Select::<Test>::new()).unwrap().remove(0);
+    assert!(Rc::ptr_eq(&t.inner(), &t1.inner()));
+    assert!(Rc::ptr_eq(&t1.inner(), &t2.inner()));
+
+    t.set_int_val(300);
+    assert!(session.flush().unwrap() == 1);
+    session.delete(&t).unwrap();
+    session.delete(&ptr).unwrap();
+    assert!(session.close().unwrap() == 2);
+    assert!(session.status() == orm::SessionStatus::Closed);
+    // 关闭之后的操作都返回错误
+    assert!(session.get::<Test>(id).is_err());
+    assert!(session.query(&Select::<Test>::new()).is_err());
+    assert!(session.insert(&Test::default()).is_err());
+    assert!(session.flush().is_err());
+    assert!(session.close().is_err());
+    assert!(db.query(&Select::<Test>::new()).unwrap().len() == 0);
+    assert!(db.query(&Select::<Ptr>::new()).unwrap().len() == 0);
+}
+
+
+pub fn lazy_test() {
+    let db = open_db();
+    db.rebuild();
+
+    let mut t = Test::default();
+    t.set_int_val(100);
+    t.set_str_val("hello world");
+    t.set_ptr(&Ptr::default());
+    t.get_ptr().set_int_val(200);
+    t.set_oo(&Oo::default());
+    t.get_oo().set_int_val(300);
+    t.set_om(vec![Om::default(), Om::default()]);
+    t.get_om().get_mut(0).unwrap().set_int_val(400);
+    t.get_om().get_mut(1).unwrap().set_int_val(500);
+    db.execute(&insert_refers(), &t).unwrap();
+
+    let session = db.open_session();
+    let t = session.get::<Test>(t.get_id()).unwrap().unwrap();
+    assert!(t.get_ptr().get_int_val() == 200);
+    assert!(t.get_om().len() == 2);
+    assert!(t.get_om()[1].get_int_val() == 500);
+    session.close().unwrap();
+
+    // 游离态下访问没有加载过的字段
+    assert!(t.load("oo").is_err());
+    assert!(t.load("ptr").is_ok());
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| t.get_oo()));
+    assert!(res.is_err());
+    assert!(t.get_ptr().get_int_val() == 200);
+
+    // eager的字段没有加载时不会懒加载
+    let session = db.open_session();
+    let mut select = Select::<Test>::new();
+    select.no_eager();
+    let t = session.query(&select).unwrap().remove(0);
+    assert!(t.load("ptr").is_err());
+    assert!(t.get_om().len() == 2);
+    session.close().unwrap();
+
+    // session没有close直接释放, 对象同样变为游离态
+    let session = db.open_session();
+    let t = session.get::<Test>(t.get_id()).unwrap().unwrap();
+    drop(session);
+    assert!(t.load("oo").is_err());
+
+    // 没有加载的one_many先懒加载, 替换时原来的对象解除关系
+    let session = db.open_session();
+    let mut select = Select::<Test>::new();
+    select.no_eager();
+    let t = session.query(&select).unwrap().remove(0);
+    assert!(!t.is_om_null());
+    assert!(t.is_loaded("om"));
+    session.close().unwrap();
+    let session = db.open_session();
+    let t = session.query(&select).unwrap().remove(0);
+    assert!(!t.is_loaded("om"));
+    t.set_om(Vec::new());
+    session.close().unwrap();
+    let vec = db.query(&Select::<Om>::new()).unwrap();
+    assert!(vec.len() == 2);
+    assert!(vec.iter().all(|om| om.is_test_id_null()));
+}
+
+
+pub fn eager_test() {
+    let db = open_db();
+    db.rebuild();
+
+    let mut t = Test::default();
+    t.set_int_val(100);
+    t.set_str_val("hello world");
+    t.set_ptr(&Ptr::default());
+    t.get_ptr().set_int_val(200);
+    db.execute(&insert_refers(), &t).unwrap();
+
+    // ptr和test都是fetch(eager)，不需要with
+    let select = Select::<Test>::new();
+    let t = db.query(&select).unwrap().remove(0);
+    assert!(t.get_ptr().get_int_val() == 200);
+    assert!(t.is_test_null());
+
+    let mut select = Select::<Test>::new();
+    select.no_eager();
+    let t = db.query(&select).unwrap().remove(0);
+    assert!(!t.do_inner(|inner| inner.field_map.contains_key("ptr")));
+}
+
+
+pub fn cond_or_test() {
+    let db = open_db();
+    db.rebuild();
+
+    for i in 1..5 {
+        let mut t = Test::default();
+        t.set_int_val(i * 100);
+        t.set_str_val(&format!("str{}", i));
+        db.execute(&Execute::insert(), &t).unwrap();
+    }
+
+    // int_val = 100 OR str_val = 'str3'
+    let mut cond = Cond::by_eq("int_val", 100);
+    cond.or(&Cond::by_eq("str_val", "str3"));
+    let mut select = Select::<Test>::new();
+    select.wher(&cond);
+    let vec = db.query(&select).unwrap();
+    assert!(vec.len() == 2);
+    assert!(vec[0].get_id() == 1);
+    assert!(vec[1].get_id() == 3);
+
+    // NOT (int_val < 300) AND (id = 3 OR str_val = 'str4')
+    let mut cond = Cond::by_not(&Cond::by_lt("int_val", 300));
+    cond.group(&Cond::any_of(vec![Cond::by_id(3), Cond::by_eq("str_val", "str4")]));
+    let mut select = Select::<Test>::new();
+    select.wher(&cond);
+    let vec = db.query(&select).unwrap();
+    assert!(vec.len() == 2);
+    assert!(vec[0].get_int_val() == 300);
+    assert!(vec[1].get_int_val() == 400);
+
+    // 空的条件上调用or, 只有or的条件
+    let mut cond = Cond::new();
+    cond.or(&Cond::by_eq("int_val", 200));
+    let mut select = Select::<Test>::new();
+    select.wher(&cond);
+    let vec = db.query(&select).unwrap();
+    assert!(vec.len() == 1);
+    assert!(vec[0].get_id() == 2);
+}
+
+
+pub fn cond_op_test() {
+    let db = open_db();
+    db.rebuild();
+
+    for i in 1..6 {
+        let mut t = Test::default();
+        t.set_int_val(i * 100);
+        t.set_str_val(&format!("{}%_str", i));
+        db.execute(&Execute::insert(), &t).unwrap();
+    }
+
+    let query = |cond: &Cond| {
+        let mut select = Select::<Test>::new();
+        select.wher(cond);
+        db.query(&select).unwrap().into_iter().map(|t| t.get_id()).collect::<Vec<_>>()
+    };
+    assert!(query(&Cond::by_ge("int_val", 400)) == vec![4, 5]);
+    assert!(query(&Cond::by_le("int_val", 200)) == vec![1, 2]);
+    assert!(query(&Cond::by_between("int_val", 200, 300)) == vec![2, 3]);
+    assert!(query(&Cond::by_in_list("int_val", vec![100, 500])) == vec![1, 5]);
+    assert!(query(&Cond::by_not_in("int_val", vec![100, 500])) == vec![2, 3, 4]);
+    assert!(query(&Cond::by_in_list::<i32>("int_val", vec![])).len() == 0);
+    assert!(query(&Cond::by_not_in::<i32>("int_val", vec![])).len() == 5);
+    assert!(query(&Cond::by_like("str_val", &Cond::starts_with("3%_"))) == vec![3]);
+    assert!(query(&Cond::by_not_like("str_val", "%str")).len() == 0);
+}
+
+
+pub fn cond_param_test() {
+    let db = open_db();
+    db.rebuild();
+
+    for i in 1..6 {
+        let mut t = Test::default();
+        t.set_int_val(i * 100);
+        t.set_str_val("hello world");
+        db.execute(&Execute::insert(), &t).unwrap();
+    }
+
+    // 同一个字段出现多次，参数不能互相覆盖
+    let mut cond = Cond::by_gt("int_val", 100);
+    cond.lt("int_val", 400);
+    let mut select = Select::<Test>::new();
+    select.wher(&cond);
+    let vec = db.query(&select).unwrap();
+    assert!(vec.len() == 2);
+    assert!(vec[0].get_id() == 2);
+    assert!(vec[1].get_id() == 3);
+
+    let mut select = Select::<Test>::new();
+    select.wher(&Cond::any_of(vec![Cond::by_id(1), Cond::by_id(5)]));
+    let params = select.get_impl().get_params();
+    assert!(params.len() == 2);
+    assert!(params[0].0 != params[1].0);
+    let vec = db.query(&select).unwrap();
+    assert!(vec.len() == 2);
+    assert!(vec[1].get_id() == 5);
+}
+
+
+pub fn cond_field_test() {
+    let db = open_db();
+    db.rebuild();
+
+    let mut t = Test::default();
+    t.set_int_val(100);
+    t.set_str_val("hello world");
+    db.execute(&Execute::insert(), &t).unwrap();
+
+    let mut select = Select::<Test>::new();
+    select.wher(&Cond::by_eq("no_such_field", 1));
+    assert!(db.query(&select).is_err());
+
+    let mut select = Select::<Test>::new();
+    select.wher(&Cond::by_eq("ptr", 1));
+    assert!(db.query(&select).is_err());
+
+    // 聚合只能用在having中
+    let mut select = Select::<Test>::new();
+    select.wher(&Cond::by_gt("count(*)", 0));
+    assert!(db.query(&select).is_err());
+
+    let mut select = Select::<Test>::new();
+    select.wher(&Cond::by_is_null("ptr_id"));
+    assert!(db.query(&select).unwrap().len() == 1);
+}
+
+
+pub fn cond_path_test() {
+    let db = open_db();
+    db.rebuild();
+
+    for i in 1..4 {
+        let mut t = Test::default();
+        t.set_int_val(i * 100);
+        t.set_str_val("hello world");
+        t.set_ptr(&Ptr::default());
+        t.get_ptr().set_int_val((i * 1000) as i64);
+        t.set_om(vec![Om::default()]);
+        t.get_om().get_mut(0).unwrap().set_int_val((i * 10) as i64);
+        db.execute(&insert_refers(), &t).unwrap();
+    }
+
+    // ptr是eager的，直接复用已有的JOIN
+    let mut select = Select::<Test>::new();
+    select.wher(&Cond::by_eq("ptr.int_val", 2000));
+    let vec = db.query(&select).unwrap();
+    assert!(vec.len() == 1);
+    assert!(vec[0].get_id() == 2);
+    assert!(vec[0].get_ptr().get_int_val() == 2000);
+
+    // om没有with，自动LEFT JOIN但不加载
+    let mut select = Select::<Test>::new();
+    select.no_eager();
+    select.wher(&Cond::by_gt("om.int_val", 10));
+    let vec = db.query(&select).unwrap();
+    assert!(vec.len() == 2);
+    assert!(!vec[0].do_inner(|inner| inner.field_map.contains_key("om")));
+
+    let mut select = Select::<Test>::new();
+    select.wher(&Cond::by_eq("int_val.id", 1));
+    assert!(db.query(&select).is_err());
+}
+
+
+pub fn cond_subquery_test() {
+    let db = open_db();
+    db.rebuild();
+
+    for i in 1..4 {
+        let mut t = Test::default();
+        t.set_int_val(i * 100);
+        t.set_str_val("hello world");
+        t.set_ptr(&Ptr::default());
+        t.get_ptr().set_int_val((i * 1000) as i64);
+        let om = (0..i).map(|_| Om::default()).collect::<Vec<_>>();
+        t.set_om(om);
+        db.execute(&insert_refers(), &t).unwrap();
+    }
+
+    // 存在id大于1的om的test
+    let mut sub = Select::<Om>::new();
+    sub.wher(&Cond::by_gt("id", 1));
+    sub.correlate(&JoinCond::by_eq("id", "test_id"));
+    let mut select = Select::<Test>::new();
+    select.wher(&Cond::exists(&sub));
+    let vec = db.query(&select).unwrap();
+    assert!(vec.iter().map(|t| t.get_id()).collect::<Vec<_>>() == vec![2, 3]);
+
+    let mut select = Select::<Test>::new();
+    select.wher(&Cond::not_exists(&sub));
+    let vec = db.query(&select).unwrap();
+    assert!(vec.len() == 1);
+    assert!(vec[0].get_id() == 1);
+
+    let mut sub = Select::<Ptr>::new();
+    sub.wher(&Cond::by_ge("int_val", 2000));
+    let mut select = Select::<Test>::new();
+    let mut cond = Cond::in_select("ptr_id", &sub);
+    cond.lt("int_val", 300);
+    select.wher(&cond);
+    let vec = db.query(&select).unwrap();
+    assert!(vec.len() == 1);
+    assert!(vec[0].get_ptr().get_int_val() == 2000);
+
+    // 关联条件左边按外层检查, 右边按子查询检查
+    for &(f1, f2) in [("xxx", "test_id"), ("id", "ptr_id"), ("id", "om.test_id")].iter() {
+        let mut sub = Select::<Om>::new();
+        sub.correlate(&JoinCond::by_eq(f1, f2));
+        let mut select = Select::<Test>::new();
+        select.wher(&Cond::exists(&sub));
+        assert!(db.query(&select).is_err());
+    }
+}
+
+
+pub fn order_limit_test() {
+    let db = open_db();
+    db.rebuild();
+
+    for i in 1..6 {
+        let mut t = Test::default();
+        t.set_int_val(i % 3);
+        t.set_str_val(&format!("str{}", i));
+        t.set_om(vec![Om::default(), Om::default()]);
+        t.get_om().get_mut(0).unwrap().set_int_val(i as i64);
+        t.get_om().get_mut(1).unwrap().set_int_val((i * 10) as i64);
+        db.execute(&insert_refers(), &t).unwrap();
+    }
+
+    // int_val: 1 2 0 1 2
+    let mut select = Select::<Test>::new();
+    select.order_by("int_val", Order::Desc).order_by("id", Order::Asc);
+    let vec = db.query(&select).unwrap();
+    assert!(vec.iter().map(|t| t.get_id()).collect::<Vec<_>>() == vec![2, 5, 1, 4, 3]);
+
+    let mut select = Select::<Test>::new();
+    select.order_by("id", Order::Desc).limit(2).offset(1);
+    let vec = db.query(&select).unwrap();
+    assert!(vec.iter().map(|t| t.get_id()).collect::<Vec<_>>() == vec![4, 3]);
+
+    // one_many的with使行数翻倍，limit仍然按照根对象计算
+    let mut select = Select::<Test>::new();
+    select.order_by("id", Order::Asc).limit(2);
+    select.with("om").order_by("int_val", Order::Desc);
+    let vec = db.query(&select).unwrap();
+    assert!(vec.len() == 2);
+    assert!(vec[0].get_om().len() == 2);
+    assert!(vec[0].get_om()[0].get_int_val() == 10);
+    assert!(vec[1].get_om()[0].get_int_val() == 20);
+}
+
+
+pub fn page_test() {
+    let db = open_db();
+    db.rebuild();
+
+    for i in 1..6 {
+        let mut t = Test::default();
+        t.set_int_val(i * 100);
+        t.set_str_val("hello world");
+        t.set_om(vec![Om::default(), Om::default()]);
+        db.execute(&insert_refers(), &t).unwrap();
+    }
+
+    let mut select = Select::<Test>::new();
+    select.wher(&Cond::by_gt("int_val", 100));
+    select.order_by("id", Order::Asc);
+    select.with("om");
+    let page = db.page(&select, 1, 3).unwrap();
+    assert!(page.total == 4);
+    assert!(page.page_count == 2);
+    assert!(page.has_next);
+    assert!(page.items.iter().map(|t| t.get_id()).collect::<Vec<_>>() == vec![2, 3, 4]);
+    assert!(page.items[0].get_om().len() == 2);
+
+    let page = db.page(&select, 2, 3).unwrap();
+    assert!(!page.has_next);
+    assert!(page.items.len() == 1);
+    assert!(page.items[0].get_id() == 5);
+
+    // 页码和每页数量从1开始, 为0时报错
+    assert!(db.page(&select, 0, 3).is_err());
+    assert!(select.page(&mut db.get_conn(), 1, 0).is_err());
+}
+
+
+pub fn aggregate_test() {
+    let db = open_db();
+    db.rebuild();
+
+    for i in 1..5 {
+        let mut t = Test::default();
+        t.set_int_val(i * 100);
+        t.set_str_val(&format!("str{}", i % 2));
+        t.set_ptr(&Ptr::default());
+        t.get_ptr().set_int_val(i as i64);
+        let om = (0..i).map(|_| Om::default()).collect::<Vec<_>>();
+        t.set_om(om);
+        db.execute(&insert_refers(), &t).unwrap();
+    }
+
+    let mut conn = db.get_conn();
+    let mut select = Select::<Test>::new();
+    assert!(select.count(&mut conn).unwrap() == 4);
+    assert!(select.count_distinct(&mut conn, "str_val").unwrap() == 2);
+    assert!(select.sum::<_, i64>(&mut conn, "int_val").unwrap() == 1000);
+    assert!(select.avg::<_, f64>(&mut conn, "int_val").unwrap() == 250.0);
+    assert!(select.min::<_, i32>(&mut conn, "int_val").unwrap() == 100);
+    assert!(select.max::<_, i64>(&mut conn, "ptr.int_val").unwrap() == 4);
+
+    select.wher(&Cond::by_gt("int_val", 1000));
+    assert!(select.count(&mut conn).unwrap() == 0);
+    assert!(select.sum::<_, Option<i64>>(&mut conn, "int_val").unwrap().is_none());
+
+    // with的one_many不会使根对象的行数相乘
+    let mut select = Select::<Test>::new();
+    select.with("om");
+    assert!(select.count(&mut conn).unwrap() == 4);
+    assert!(select.sum::<_, i64>(&mut conn, "int_val").unwrap() == 1000);
+    assert!(select.avg::<_, f64>(&mut conn, "int_val").unwrap() == 250.0);
+    select.wher(&Cond::by_gt("int_val", 200));
+    assert!(select.sum::<_, i64>(&mut conn, "int_val").unwrap() == 700);
+}
+
+
+pub fn group_test() {
+    let db = open_db();
+    db.rebuild();
+
+    for i in 1..4 {
+        let mut t = Test::default();
+        t.set_int_val(i * 100);
+        t.set_str_val("hello world");
+        let om = (0..i).map(|_| Om::default()).collect::<Vec<_>>();
+        t.set_om(om);
+        for om in t.get_om().iter_mut() {
+            om.set_int_val(i as i64);
+        }
+        db.execute(&insert_refers(), &t).unwrap();
+    }
+
+    // 每个test_id下om的数量
+    let mut select = Select::<Om>::new();
+    select.group_by(&["test_id"]).having(&Cond::by_gt("count(*)", 1));
+    select.order_by("test_id", Order::Asc);
+    let rows = select.project::<_, (u64, u64, i64)>(&mut db.get_conn(),
+                                                    &["test_id", "count(*)", "sum(int_val)"])
+        .unwrap();
+    assert!(rows == vec![(2, 2, 4), (3, 3, 9)]);
+}
+
+
+struct TestDto {
+    id: u64,
+    str_val: String,
+    ptr_val: Option<i64>,
+}
+
+impl mysql::prelude::FromRow for TestDto {
+    fn from_row(row: mysql::Row) -> Self {
+        Self::from_row_opt(row).unwrap()
+    }
+    fn from_row_opt(row: mysql::Row) -> Result<Self, mysql::FromRowError> {
+        mysql::from_row_opt::<(u64, String, Option<i64>)>(row).map(|(id, str_val, ptr_val)| {
+            TestDto {
+                id: id,
+                str_val: str_val,
+                ptr_val: ptr_val,
+            }
+        })
+    }
+}
+
+impl Projection for TestDto {
+    fn fields() -> Vec<&'static str> {
+        vec!["id", "str_val", "ptr.int_val"]
+    }
+}
+
+pub fn columns_test() {
+    let db = open_db();
+    db.rebuild();
+
+    let mut t = Test::default();
+    t.set_int_val(100);
+    t.set_str_val("hello world");
+    t.set_ptr(&Ptr::default());
+    t.get_ptr().set_int_val(200);
+    db.execute(&insert_refers(), &t).unwrap();
+
+    let mut select = Select::<Test>::new();
+    select.no_eager();
+    select.columns(&["str_val"]);
+    let t = db.query(&select).unwrap().remove(0);
+    assert!(t.get_id() == 1);
+    assert!(t.get_str_val() == "hello world");
+    assert!(!t.is_loaded("int_val"));
+    assert!(t.is_loaded("str_val"));
+    // 外键总是加载, 之后可以加载关系
+    assert!(t.is_loaded("ptr_id"));
+
+    // 同一个session中先查部分字段, 之后完整查询时补上没有加载的字段
+    let session = db.open_session();
+    let t1 = session.query(&select).unwrap().remove(0);
+    assert!(!t1.is_loaded("int_val"));
+    let t2 = session.query(&Select::<Test>::new()).unwrap().remove(0);
+    assert!(Rc::ptr_eq(&t1.inner(), &t2.inner()));
+    assert!(t2.get_int_val() == 100);
+    assert!(t2.get_ptr().get_int_val() == 200);
+    session.close().unwrap();
+
+    let select = Select::<Test>::new();
+    let vec = select.map_into::<_, TestDto>(&mut db.get_conn()).unwrap();
+    assert!(vec.len() == 1);
+    assert!(vec[0].id == 1);
+    assert!(vec[0].str_val == "hello world");
+    assert!(vec[0].ptr_val == Some(200));
+}
+
+
+pub fn iter_test() {
+    let db = open_db();
+    db.rebuild();
+
+    for i in 1..4 {
+        let mut t = Test::default();
+        t.set_int_val(i * 100);
+        t.set_str_val("hello world");
+        let om = (0..i).map(|_| Om::default()).collect::<Vec<_>>();
+        t.set_om(om);
+        db.execute(&insert_refers(), &t).unwrap();
+    }
+
+    let mut conn = db.get_conn();
+    let mut select = Select::<Test>::new();
+    select.with("om");
+    let vec = select.iter(&mut conn).unwrap().map(Result::unwrap).collect::<Vec<_>>();
+    assert!(vec.len() == 3);
+    for (i, t) in vec.iter().enumerate() {
+        assert!(t.get_id() == (i + 1) as u64);
+        assert!(t.get_om().len() == i + 1);
+    }
+    // 和query的展开方式一致, eager的ptr也已经加载
+    assert!(vec.iter().all(|t| t.is_loaded("ptr")));
+
+    // batch的with不能流式读取
+    let mut select = Select::<Test>::new();
+    select.with("om").batch();
+    assert!(select.iter(&mut conn).is_err());
+}
+
+
+pub fn join2_test() {
+    let db = open_db();
+    db.rebuild();
+
+    for i in 1..4 {
+        let mut t = Test::default();
+        t.set_int_val(i * 100);
+        t.set_str_val("hello world");
+        if i != 2 {
+            t.set_ptr(&Ptr::default());
+            t.get_ptr().set_int_val((i * 1000) as i64);
+        }
+        db.execute(&insert_refers(), &t).unwrap();
+    }
+
+    let mut select = Select::<Test>::new();
+    select.no_eager();
+    let mut select = select.left_join2::<Ptr>(&JoinCond::by_eq("ptr_id", "id"));
+    select.get_join().order_by("int_val", Order::Asc);
+    let vec = select.query(&mut db.get_conn()).unwrap();
+    assert!(vec.len() == 3);
+    let (ref t, ref p) = vec[0];
+    assert!(t.get_int_val() == 200);
+    assert!(p.is_none());
+    let (ref t, ref p) = vec[2];
+    assert!(t.get_int_val() == 300);
+    assert!(p.as_ref().unwrap().get_int_val() == 3000);
+
+    // 没有被引用的ptr, right join时test为None
+    let mut p = Ptr::default();
+    p.set_int_val(4000);
+    db.execute(&Execute::insert(), &p).unwrap();
+    let mut select = Select::<Test>::new();
+    select.no_eager();
+    let mut select = select.right_join2::<Ptr>(&JoinCond::by_eq("ptr_id", "id"));
+    select.get_join().order_by("int_val", Order::Asc);
+    let vec = select.query_opt(&mut db.get_conn()).unwrap();
+    assert!(vec.len() == 3);
+    let (ref t, ref p) = vec[2];
+    assert!(t.is_none());
+    assert!(p.as_ref().unwrap().get_int_val() == 4000);
+    assert!(select.query(&mut db.get_conn()).unwrap().len() == 2);
+}
+
+
+pub fn join_rel_test() {
+    let db = open_db();
+    db.rebuild();
+
+    for i in 1..4 {
+        let mut t = Test::default();
+        t.set_int_val(i * 100);
+        t.set_str_val("hello world");
+        t.set_ptr(&Ptr::default());
+        t.get_ptr().set_int_val((i * 1000) as i64);
+        let om = (0..i).map(|_| Om::default()).collect::<Vec<_>>();
+        t.set_om(om);
+        db.execute(&insert_refers(), &t).unwrap();
+    }
+
+    let mut select = Select::<Test>::new();
+    select.no_eager();
+    {
+        let join = select.join_rel("ptr");
+        join.on(&Cond::by_ge("int_val", 2000));
+    }
+    let res = select.query(&mut db.get_conn()).unwrap();
+    assert!(res.iter().map(|t| t.get_id()).collect::<Vec<_>>() == vec![2, 3]);
+
+    let mut select = Select::<Test>::new();
+    select.no_eager();
+    select.left_join_rel("om").wher(&Cond::by_gt("id", 3));
+    let res = select.query(&mut db.get_conn()).unwrap();
+    assert!(res.iter().map(|t| t.get_id()).collect::<Vec<_>>() == vec![3]);
+
+    // 字段不存在或者不是关系时, 查询返回错误
+    let mut select = Select::<Test>::new();
+    select.join_rel("xxx");
+    assert!(select.query(&mut db.get_conn()).is_err());
+    let mut select = Select::<Test>::new();
+    select.join_rel("int_val");
+    assert!(select.count(&mut db.get_conn()).is_err());
+}
+
+
+pub fn join_as_test() {
+    let db = open_db();
+    db.rebuild();
+
+    for i in 1..4 {
+        let mut t = Test::default();
+        t.set_int_val(i * 100);
+        t.set_str_val("hello world");
+        db.execute(&Execute::insert(), &t).unwrap();
+    }
+
+    // 同一个entity JOIN两次, 自动生成的别名不重复
+    let mut select = Select::<Test>::new();
+    select.no_eager();
+    select.join::<Test>(&JoinCond::by_gt("int_val", "int_val"));
+    select.join::<Test>(&JoinCond::by_gt("int_val", "int_val"));
+    let res = select.query(&mut db.get_conn()).unwrap();
+    assert!(res.iter().map(|t| t.get_id()).collect::<Vec<_>>() == vec![2, 3]);
+
+    // 指定别名, t3的条件引用根表test
+    let mut select = Select::<Test>::new();
+    select.no_eager();
+    select.join_as::<Test>("t2", &JoinCond::by_lt("int_val", "int_val"))
+        .join_as::<Test>("t3", &JoinCond::by_gt("test.int_val", "int_val"));
+    let res = select.query(&mut db.get_conn()).unwrap();
+    assert!(res.iter().map(|t| t.get_id()).collect::<Vec<_>>() == vec![2]);
+}
+
+pub fn with_filter_test() {
+    let db = open_db();
+    db.rebuild();
+
+    for i in 1..4 {
+        let mut t = Test::default();
+        t.set_int_val(i * 100);
+        t.set_str_val("hello world");
+        let om = (0..i).map(|_| Om::default()).collect::<Vec<_>>();
+        t.set_om(om);
+        db.execute(&insert_refers(), &t).unwrap();
+    }
+
+    // filter不过滤外层对象, 没有满足条件的om时为空的Vec
+    let mut select = Select::<Test>::new();
+    select.no_eager();
+    select.order_by("id", Order::Asc);
+    select.with("om").filter(&Cond::by_gt("id", 3)).order_by("id", Order::Desc);
+    let vec = select.query(&mut db.get_conn()).unwrap();
+    assert!(vec.len() == 3);
+    assert!(vec[0].get_om().len() == 0);
+    assert!(vec[1].get_om().len() == 0);
+    assert!(vec[2].get_om().iter().map(|om| om.get_id()).collect::<Vec<_>>() == vec![6, 5, 4]);
+
+    // wher会把外层对象也过滤掉
+    let mut select = Select::<Test>::new();
+    select.no_eager();
+    select.with("om").wher(&Cond::by_gt("id", 3));
+    let vec = select.query(&mut db.get_conn()).unwrap();
+    assert!(vec.len() == 1);
+
+    // 根对象和join的对象上不能用filter
+    let mut select = Select::<Test>::new();
+    select.wher(&Cond::by_gt("id", 1)).filter(&Cond::by_gt("id", 1));
+    assert!(select.query(&mut db.get_conn()).is_err());
+    let mut select = Select::<Test>::new();
+    select.join_as::<Test>("t2", &JoinCond::by_lt("int_val", "int_val"))
+        .wher(&Cond::by_gt("id", 1))
+        .filter(&Cond::by_gt("id", 1));
+    assert!(select.query(&mut db.get_conn()).is_err());
+}
+
+pub fn batch_test() {
+    let db = open_db();
+    db.rebuild();
+
+    for i in 1..4 {
+        let mut t = Test::default();
+        t.set_int_val(i * 100);
+        t.set_str_val("hello world");
+        let om = (0..i).map(|j| {
+                let mut om = Om::default();
+                om.set_int_val(j as i64);
+                om
+            })
+            .collect::<Vec<_>>();
+        t.set_om(om);
+        db.execute(&insert_refers(), &t).unwrap();
+    }
+
+    let mut select = Select::<Test>::new();
+    select.no_eager();
+    select.order_by("id", Order::Asc);
+    select.with("om").batch().order_by("id", Order::Desc);
+    let vec = select.query(&mut db.get_conn()).unwrap();
+    assert!(vec.len() == 3);
+    assert!(vec[0].get_om().iter().map(|om| om.get_id()).collect::<Vec<_>>() == vec![1]);
+    assert!(vec[1].get_om().iter().map(|om| om.get_id()).collect::<Vec<_>>() == vec![3, 2]);
+    assert!(vec[2].get_om().iter().map(|om| om.get_id()).collect::<Vec<_>>() == vec![6, 5, 4]);
+
+    // 单独查询时wher只过滤om, 不过滤test
+    let mut select = Select::<Test>::new();
+    select.no_eager();
+    select.order_by("id", Order::Asc).limit(2);
+    select.with("om").batch().wher(&Cond::by_gt("int_val", 0));
+    let vec = select.query(&mut db.get_conn()).unwrap();
+    assert!(vec.len() == 2);
+    assert!(vec[0].get_om().len() == 0);
+    assert!(vec[1].get_om().iter().map(|om| om.get_id()).collect::<Vec<_>>() == vec![3]);
+    // 计数和分页同样不受batch的条件影响
+    assert!(select.count(&mut db.get_conn()).unwrap() == 3);
+    let page = select.page(&mut db.get_conn(), 1, 2).unwrap();
+    assert!(page.total == 3);
+    assert!(page.items[1].get_om().len() == 1);
+
+    // join的对象上的with也可以batch
+    let mut select = Select::<Test>::new();
+    select.no_eager();
+    select.wher(&Cond::by_id(1));
+    select.join_as::<Test>("t2", &JoinCond::by_lt("int_val", "int_val")).with("om").batch();
+    #[allow(deprecated)]
+    let res = select.query_ex(&mut db.get_conn()).unwrap();
+    assert!(res[1].len() == 2);
+    for t2 in res[1].iter() {
+        assert!(t2.get_om().len() as u64 == t2.get_id());
+    }
+
+    // 只有one_many的with可以batch
+    let mut select = Select::<Test>::new();
+    select.with("ptr").batch();
+    assert!(select.query(&mut db.get_conn()).is_err());
+    let mut select = Select::<Test>::new();
+    select.join_as::<Test>("t2", &JoinCond::by_lt("int_val", "int_val"))
+        .wher(&Cond::by_gt("int_val", 0))
+        .batch();
+    assert!(select.query(&mut db.get_conn()).is_err());
+}
+
+pub fn alias_cond_test() {
+    let db = open_db();
+    db.rebuild();
+
+    for i in 1..4 {
+        let mut t = Test::default();
+        t.set_int_val(i * 100);
+        t.set_str_val("hello world");
+        db.execute(&Execute::insert(), &t).unwrap();
+    }
+
+    // 根表的条件引用join的别名t2
+    let mut select = Select::<Test>::new();
+    select.no_eager();
+    select.order_by("id", Order::Asc);
+    select.wher(&Cond::by_gt("t2.int_val", 250));
+    select.join_as::<Test>("t2", &JoinCond::by_lt("int_val", "int_val"));
+    let res = select.query(&mut db.get_conn()).unwrap();
+    assert!(res.iter().map(|t| t.get_id()).collect::<Vec<_>>() == vec![1, 2]);
+    assert!(select.count(&mut db.get_conn()).unwrap() == 2);
+
+    // 不存在的别名报错
+    let mut select = Select::<Test>::new();
+    select.no_eager();
+    select.wher(&Cond::by_gt("t3.int_val", 250));
+    select.join_as::<Test>("t2", &JoinCond::by_lt("int_val", "int_val"));
+    assert!(select.query(&mut db.get_conn()).is_err());
+
+    // 指定的别名重复时报错, 不会panic
+    let mut select = Select::<Test>::new();
+    select.no_eager();
+    select.join_as::<Test>("t2", &JoinCond::by_lt("int_val", "int_val"));
+    select.join_as::<Test>("t2", &JoinCond::by_gt("int_val", "int_val"));
+    assert!(select.query(&mut db.get_conn()).is_err());
+    assert!(select.count(&mut db.get_conn()).is_err());
+
+    // JOIN条件中的别名和字段同样要检查
+    for field in ["t3.int_val", "t2.xxx", "xxx", "ptr.int_val"].iter() {
+        let mut select = Select::<Test>::new();
+        select.no_eager();
+        select.join_as::<Test>("t2", &JoinCond::by_lt("int_val", "int_val"))
+            .join_as::<Test>("t4", &JoinCond::by_gt(field, "int_val"));
+        assert!(select.query(&mut db.get_conn()).is_err());
+    }
+}
+
+pub fn session_flush_fail_test() {
+    let db = open_db();
+    db.rebuild();
+
+    let mut t = Test::default();
+    t.set_int_val(100);
+    t.set_str_val("hello world");
+    db.execute(&Execute::insert(), &t).unwrap();
+    let ptr = Ptr::default();
+    db.execute(&Execute::insert(), &ptr).unwrap();
+
+    let session = db.open_session();
+    let mut t = session.get::<Test>(t.get_id()).unwrap().unwrap();
+    t.set_int_val(200);
+    let ptr = session.get::<Ptr>(ptr.get_id()).unwrap().unwrap();
+    session.delete(&ptr).unwrap();
+    let mut t2 = Test::default();
+    t2.set_int_val(300);
+    t2.set_str_val("hello world");
+    session.insert(&t2).unwrap();
+
+    // 删除ptr时表不存在, 整个flush回滚
+    let sql = format!("DROP TABLE `{}`", Ptr::meta().table);
+    db.get_conn().query(sql).unwrap();
+    assert!(session.flush().is_err());
+    assert!(t.do_inner(|inner| inner.is_dirty()));
+    assert!(t2.do_inner(|inner| inner.get_id_u64().is_none()));
+    assert!(db.query(&Select::<Test>::new()).unwrap().len() == 1);
+
+    // 修复之后重新flush, 之前的操作都还在
+    db.create().unwrap();
+    session.flush().unwrap();
+    assert!(!t.do_inner(|inner| inner.is_dirty()));
+    assert!(t2.do_inner(|inner| inner.get_id_u64().is_some()));
+    let mut select = Select::<Test>::new();
+    select.order_by("id", Order::Asc);
+    let vec = db.query(&select).unwrap();
+    assert!(vec.iter().map(|t| t.get_int_val()).collect::<Vec<_>>() == vec![200, 300]);
+    session.close().unwrap();
+}
+
 // pub fn update_test() {
 //     let db = open_db();
 //     db.rebuild();
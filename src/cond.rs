@@ -1,4 +1,94 @@
 use mysql::Value;
+use mysql::Error;
+
+use meta::EntityMeta;
+use meta::FieldMeta;
+use meta::OrmMeta;
+use entity::Entity;
+use select::Select;
+use select::SelectImpl;
+
+use std::mem;
+use std::io;
+use std::collections::HashMap;
+use std::fmt;
+use std::error;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CondError {
+    // (entity, field)
+    UnknownField(String, String),
+    ReferField(String, String),
+    NotReferField(String, String),
+    PathInJoinOn(String, String),
+    // (alias)
+    DuplicateAlias(String),
+    // (entity, field)
+    NotOneToManyField(String, String),
+    // (entity)
+    BatchNotWith(String),
+    // (path)
+    BatchInIter(String),
+    // (entity)
+    FilterNotWith(String),
+}
+
+// 语句中所有的别名 -> entity, 条件中可以用"alias.field"引用其他表
+pub type Scope = HashMap<String, &'static EntityMeta>;
+
+impl fmt::Display for CondError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &CondError::UnknownField(ref entity, ref field) => {
+                write!(f, "Unknown Field [{}.{}] In Cond", entity, field)
+            }
+            &CondError::ReferField(ref entity, ref field) => {
+                write!(f, "Can't Compare Refer Field [{}.{}] In Cond", entity, field)
+            }
+            &CondError::NotReferField(ref entity, ref field) => {
+                write!(f, "Field [{}.{}] In Cond Path Is Not A Refer Field", entity, field)
+            }
+            &CondError::PathInJoinOn(ref entity, ref field) => {
+                write!(f, "Can't Use Path [{}] Of [{}] In Join On Cond", field, entity)
+            }
+            &CondError::DuplicateAlias(ref alias) => write!(f, "Duplicate Alias [{}]", alias),
+            &CondError::NotOneToManyField(ref entity, ref field) => {
+                write!(f, "Batch Field [{}.{}] Is Not A OneToMany Field", entity, field)
+            }
+            &CondError::BatchNotWith(ref entity) => {
+                write!(f, "Batch Can Only Be Used On With, [{}] Is Not A With", entity)
+            }
+            &CondError::BatchInIter(ref path) => {
+                write!(f, "Can't Use Batch With [{}] In Iter", path)
+            }
+            &CondError::FilterNotWith(ref entity) => {
+                write!(f, "Filter Can Only Be Used On With, [{}] Is Not A With", entity)
+            }
+        }
+    }
+}
+
+impl error::Error for CondError {
+    fn description(&self) -> &str {
+        match self {
+            &CondError::UnknownField(..) => "Unknown Field In Cond",
+            &CondError::ReferField(..) => "Can't Compare Refer Field In Cond",
+            &CondError::NotReferField(..) => "Field In Cond Path Is Not A Refer Field",
+            &CondError::PathInJoinOn(..) => "Can't Use Path In Join On Cond",
+            &CondError::DuplicateAlias(..) => "Duplicate Alias",
+            &CondError::NotOneToManyField(..) => "Batch Field Is Not A OneToMany Field",
+            &CondError::BatchNotWith(..) => "Batch Can Only Be Used On With",
+            &CondError::BatchInIter(..) => "Can't Use Batch With In Iter",
+            &CondError::FilterNotWith(..) => "Filter Can Only Be Used On With",
+        }
+    }
+}
+
+impl From<CondError> for Error {
+    fn from(err: CondError) -> Error {
+        Error::IoError(io::Error::new(io::ErrorKind::InvalidInput, err))
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Cond {
@@ -45,6 +135,51 @@ impl Cond {
         cond.lt(field, value);
         cond
     }
+    pub fn by_ge<V>(field: &str, value: V) -> Self
+        where Value: From<V>
+    {
+        let mut cond = Cond::new();
+        cond.ge(field, value);
+        cond
+    }
+    pub fn by_le<V>(field: &str, value: V) -> Self
+        where Value: From<V>
+    {
+        let mut cond = Cond::new();
+        cond.le(field, value);
+        cond
+    }
+    pub fn by_in_list<V>(field: &str, values: Vec<V>) -> Self
+        where Value: From<V>
+    {
+        let mut cond = Cond::new();
+        cond.in_list(field, values);
+        cond
+    }
+    pub fn by_not_in<V>(field: &str, values: Vec<V>) -> Self
+        where Value: From<V>
+    {
+        let mut cond = Cond::new();
+        cond.not_in(field, values);
+        cond
+    }
+    pub fn by_between<V>(field: &str, lo: V, hi: V) -> Self
+        where Value: From<V>
+    {
+        let mut cond = Cond::new();
+        cond.between(field, lo, hi);
+        cond
+    }
+    pub fn by_like(field: &str, pattern: &str) -> Self {
+        let mut cond = Cond::new();
+        cond.like(field, pattern);
+        cond
+    }
+    pub fn by_not_like(field: &str, pattern: &str) -> Self {
+        let mut cond = Cond::new();
+        cond.not_like(field, pattern);
+        cond
+    }
     pub fn by_is_null(field: &str) -> Self {
         let mut cond = Cond::new();
         cond.is_null(field);
@@ -55,6 +190,36 @@ impl Cond {
         cond.not_null(field);
         cond
     }
+    pub fn by_not(cond: &Cond) -> Self {
+        let mut ret = Cond::new();
+        ret.not(cond);
+        ret
+    }
+    // EXISTS (SELECT 1 FROM ...), 与外层的关联通过select.correlate指定
+    pub fn exists<E>(select: &Select<E>) -> Self
+        where E: Entity
+    {
+        Cond { items: vec![Item::Exists(select.get_impl().clone())] }
+    }
+    pub fn not_exists<E>(select: &Select<E>) -> Self
+        where E: Entity
+    {
+        Cond { items: vec![Item::NotExists(select.get_impl().clone())] }
+    }
+    // field IN (SELECT id FROM ...)
+    pub fn in_select<E>(field: &str, select: &Select<E>) -> Self
+        where E: Entity
+    {
+        Cond { items: vec![Item::InSelect(field.to_string(), select.get_impl().clone())] }
+    }
+    // (c1 OR c2 OR ...)
+    pub fn any_of(conds: Vec<Cond>) -> Self {
+        Cond { items: vec![Item::Or(conds)] }
+    }
+    // (c1 AND c2 AND ...)
+    pub fn all_of(conds: Vec<Cond>) -> Self {
+        Cond { items: vec![Item::And(conds)] }
+    }
 }
 
 impl Cond {
@@ -88,6 +253,47 @@ impl Cond {
         self.items.push(Item::Lt(field.to_string(), Value::from(value)));
         self
     }
+    pub fn ge<V>(&mut self, field: &str, value: V) -> &mut Self
+        where Value: From<V>
+    {
+        self.items.push(Item::Ge(field.to_string(), Value::from(value)));
+        self
+    }
+    pub fn le<V>(&mut self, field: &str, value: V) -> &mut Self
+        where Value: From<V>
+    {
+        self.items.push(Item::Le(field.to_string(), Value::from(value)));
+        self
+    }
+    pub fn in_list<V>(&mut self, field: &str, values: Vec<V>) -> &mut Self
+        where Value: From<V>
+    {
+        let values = values.into_iter().map(Value::from).collect();
+        self.items.push(Item::In(field.to_string(), values));
+        self
+    }
+    pub fn not_in<V>(&mut self, field: &str, values: Vec<V>) -> &mut Self
+        where Value: From<V>
+    {
+        let values = values.into_iter().map(Value::from).collect();
+        self.items.push(Item::NotIn(field.to_string(), values));
+        self
+    }
+    pub fn between<V>(&mut self, field: &str, lo: V, hi: V) -> &mut Self
+        where Value: From<V>
+    {
+        self.items.push(Item::Between(field.to_string(), Value::from(lo), Value::from(hi)));
+        self
+    }
+    // pattern原样传给LIKE, 需要匹配字面量时先用escape_like转义
+    pub fn like(&mut self, field: &str, pattern: &str) -> &mut Self {
+        self.items.push(Item::Like(field.to_string(), Value::from(pattern)));
+        self
+    }
+    pub fn not_like(&mut self, field: &str, pattern: &str) -> &mut Self {
+        self.items.push(Item::NotLike(field.to_string(), Value::from(pattern)));
+        self
+    }
     pub fn is_null(&mut self, field: &str) -> &mut Self {
         self.items.push(Item::Null(field.to_string()));
         self
@@ -96,21 +302,119 @@ impl Cond {
         self.items.push(Item::NotNull(field.to_string()));
         self
     }
+    // 当前已有的条件作为一组, 与cond取OR
+    pub fn or(&mut self, cond: &Cond) -> &mut Self {
+        let items = mem::replace(&mut self.items, Vec::new());
+        let mut conds = match items.len() {
+            // 没有条件时不能加入空的一组, 空的一组是1 = 1, 会使OR永远成立
+            0 => Vec::new(),
+            1 => {
+                match items.into_iter().next().unwrap() {
+                    Item::Or(conds) => conds,
+                    item => vec![Cond { items: vec![item] }],
+                }
+            }
+            _ => vec![Cond { items: items }],
+        };
+        conds.push(cond.clone());
+        self.items.push(Item::Or(conds));
+        self
+    }
+    pub fn not(&mut self, cond: &Cond) -> &mut Self {
+        self.items.push(Item::Not(cond.clone()));
+        self
+    }
+    // 以括号包起来作为一个整体
+    pub fn group(&mut self, cond: &Cond) -> &mut Self {
+        self.items.push(Item::And(vec![cond.clone()]));
+        self
+    }
+}
+
+impl Cond {
+    // 转义LIKE中的通配符, 例如 escape_like("50%") + "%" 匹配以"50%"开头的字符串
+    pub fn escape_like(s: &str) -> String {
+        let mut ret = String::new();
+        for c in s.chars() {
+            match c {
+                '\\' | '%' | '_' => ret.push('\\'),
+                _ => {}
+            }
+            ret.push(c);
+        }
+        ret
+    }
+    pub fn starts_with(s: &str) -> String {
+        format!("{}%", Cond::escape_like(s))
+    }
+    pub fn ends_with(s: &str) -> String {
+        format!("%{}", Cond::escape_like(s))
+    }
+    pub fn contains(s: &str) -> String {
+        format!("%{}%", Cond::escape_like(s))
+    }
 }
 
 impl Cond{
-    pub fn to_sql(&self, alias: &str) -> String {
+    // 检查字段是否存在且为普通字段, 生成sql前调用
+    pub fn check(&self, meta: &EntityMeta, orm_meta: &OrmMeta) -> Result<(), CondError> {
+        self.check_ex(meta, orm_meta, &HashMap::new(), false)
+    }
+    // scope中的别名可以用"alias.field"引用
+    pub fn check_in(&self,
+                    meta: &EntityMeta,
+                    orm_meta: &OrmMeta,
+                    scope: &Scope)
+                    -> Result<(), CondError> {
+        self.check_ex(meta, orm_meta, scope, false)
+    }
+    // having中可以使用聚合, 如"count(*)", where/on中不可以
+    pub fn check_having(&self,
+                        meta: &EntityMeta,
+                        orm_meta: &OrmMeta,
+                        scope: &Scope)
+                        -> Result<(), CondError> {
+        self.check_ex(meta, orm_meta, scope, true)
+    }
+    fn check_ex(&self,
+                meta: &EntityMeta,
+                orm_meta: &OrmMeta,
+                scope: &Scope,
+                aggregate: bool)
+                -> Result<(), CondError> {
         self.items
             .iter()
-            .map(|item| item.to_sql(alias))
-            .collect::<Vec<_>>()
-            .join(" AND ")
+            .fold(Ok(()),
+                  |acc, item| acc.and_then(|_| item.check(meta, orm_meta, scope, aggregate)))
+    }
+    // "ptr.int_val"这样的路径上经过的关系, 如[["ptr"]], 查询时需要为其加上LEFT JOIN
+    pub fn get_paths(&self) -> Vec<Vec<String>> {
+        self.items.iter().flat_map(Item::get_paths).collect()
+    }
+    // 生成sql的同时把参数追加到params, 占位符按params中的序号编号, 整棵查询树内不会重名
+    pub fn to_sql(&self,
+                  alias: &str,
+                  meta: &EntityMeta,
+                  orm_meta: &OrmMeta,
+                  params: &mut Vec<(String, Value)>)
+                  -> String {
+        self.to_sql_in(alias, meta, orm_meta, &HashMap::new(), params)
     }
-    pub fn to_params(&self, alias: &str) -> Vec<(String, Value)> {
+    pub fn to_sql_in(&self,
+                     alias: &str,
+                     meta: &EntityMeta,
+                     orm_meta: &OrmMeta,
+                     scope: &Scope,
+                     params: &mut Vec<(String, Value)>)
+                     -> String {
+        if self.items.len() == 0 {
+            return "1 = 1".to_string();
+        }
         self.items
             .iter()
-            .flat_map(|item| item.to_params(alias))
+            .map(|item| item.to_sql(alias, meta, orm_meta, scope, params))
             .collect::<Vec<_>>()
+            .join(" AND ")
     }
 }
 
@@ -121,35 +425,321 @@ enum Item {
     Ne(String, Value),
     Gt(String, Value),
     Lt(String, Value),
+    Ge(String, Value),
+    Le(String, Value),
+    In(String, Vec<Value>),
+    NotIn(String, Vec<Value>),
+    Between(String, Value, Value),
+    Like(String, Value),
+    NotLike(String, Value),
     Null(String),
     NotNull(String),
+    And(Vec<Cond>),
+    Or(Vec<Cond>),
+    Not(Cond),
+    Exists(SelectImpl),
+    NotExists(SelectImpl),
+    InSelect(String, SelectImpl),
+}
+
+// alias_field_n, n为该参数在params中的位置
+fn bind(alias: &str, field: &str, value: &Value, params: &mut Vec<(String, Value)>) -> String {
+    let field = field.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    let name = format!("{}_{}_{}", alias, field, params.len());
+    params.push((name.clone(), value.clone()));
+    format!(":{}", name)
+}
+
+fn bind_list(alias: &str,
+             field: &str,
+             values: &Vec<Value>,
+             params: &mut Vec<(String, Value)>)
+             -> String {
+    values.iter()
+        .map(|value| bind(alias, field, value, params))
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
-fn concat(alias: &str, field: &str) -> String {
-    format!("{}_{}", alias, field)
+// 沿着路径找到字段所在的表, "ptr.int_val" -> (alias_ptr, Ptr, int_val)
+// 路径上的表别名与with的规则相同, 都是"父别名_字段名"
+pub fn resolve<'a>(alias: &str,
+                   meta: &'a EntityMeta,
+                   orm_meta: &'a OrmMeta,
+                   field: &str)
+                   -> Result<(String, &'a EntityMeta, &'a FieldMeta), CondError> {
+    let mut alias = alias.to_string();
+    let mut meta = meta;
+    let mut path = field.split('.').collect::<Vec<_>>();
+    let field = path.pop().unwrap();
+    for a_b_field in path {
+        let a_b_meta = match meta.field_map.get(a_b_field) {
+            Some(a_b_meta) => a_b_meta,
+            None => {
+                return Err(CondError::UnknownField(meta.entity.clone(), a_b_field.to_string()))
+            }
+        };
+        if !a_b_meta.is_type_refer() {
+            return Err(CondError::NotReferField(meta.entity.clone(), a_b_field.to_string()));
+        }
+        meta = orm_meta.entity_map.get(&a_b_meta.get_refer_entity()).unwrap();
+        alias = format!("{}_{}", alias, a_b_field);
+    }
+    match meta.field_map.get(field) {
+        None => Err(CondError::UnknownField(meta.entity.clone(), field.to_string())),
+        Some(field_meta) if field_meta.is_type_refer() => {
+            Err(CondError::ReferField(meta.entity.clone(), field.to_string()))
+        }
+        Some(field_meta) => Ok((alias, meta, field_meta)),
+    }
+}
+
+// "t2.int_val": 第一段不是当前entity的字段, 而是scope中的别名时, 引用该别名的表
+// 引用的表必须在语句中, 所以只能是"alias.field", 不能再接路径
+fn resolve_in<'a>(alias: &str,
+                  meta: &'a EntityMeta,
+                  orm_meta: &'a OrmMeta,
+                  scope: &Scope,
+                  field: &str)
+                  -> Result<(String, &'a EntityMeta, &'a FieldMeta), CondError> {
+    let mut parts = field.splitn(2, '.');
+    let first = parts.next().unwrap();
+    let is_field = meta.field_map.contains_key(first);
+    match (parts.next(), scope.get(first)) {
+        (Some(rest), Some(b_meta)) if !is_field && !rest.contains('.') => {
+            resolve(first, b_meta, orm_meta, rest)
+        }
+        _ => resolve(alias, meta, orm_meta, field),
+    }
+}
+
+// alias.column, 生成sql前已经由check检查过, 这里一定能找到字段
+fn column(alias: &str,
+          meta: &EntityMeta,
+          orm_meta: &OrmMeta,
+          scope: &Scope,
+          field: &str)
+          -> String {
+    let res = resolve_expr(alias, meta, orm_meta, scope, field);
+    debug_assert!(res.is_ok(), "{:?}", res);
+    res.unwrap_or_else(|_| format!("{}.{}", alias, field))
+}
+
+// "sum(int_val)" -> ("SUM", "int_val"), "count(*)" -> ("COUNT", "*")
+pub fn split_aggregate(expr: &str) -> Option<(String, String)> {
+    let expr = expr.trim();
+    let pos = match expr.find('(') {
+        Some(pos) if expr.ends_with(')') => pos,
+        _ => return None,
+    };
+    let func = expr[..pos].trim().to_uppercase();
+    let field = expr[pos + 1..expr.len() - 1].trim().to_string();
+    match func.as_str() {
+        "COUNT" | "SUM" | "AVG" | "MIN" | "MAX" => Some((func, field)),
+        _ => None,
+    }
+}
+
+// 字段/路径/聚合 -> sql表达式, 如"sum(ptr.int_val)" -> "SUM(test_ptr.int_val)"
+// scope中的别名可以用"alias.field"引用
+pub fn resolve_expr(alias: &str,
+                    meta: &EntityMeta,
+                    orm_meta: &OrmMeta,
+                    scope: &Scope,
+                    expr: &str)
+                    -> Result<String, CondError> {
+    let to_column = |field: &str| {
+        resolve_in(alias, meta, orm_meta, scope, field)
+            .map(|(alias, _, field_meta)| format!("{}.{}", alias, field_meta.get_column_name()))
+    };
+    match split_aggregate(expr) {
+        Some((ref func, ref field)) if func == "COUNT" && field == "*" => {
+            Ok("COUNT(*)".to_string())
+        }
+        Some((func, field)) => to_column(&field).map(|column| format!("{}({})", func, column)),
+        None => to_column(expr),
+    }
+}
+
+// 表达式中经过的关系, "sum(ptr.int_val)" -> ["ptr"]
+pub fn get_expr_path(expr: &str) -> Vec<String> {
+    let field = split_aggregate(expr).map_or(expr.to_string(), |(_, field)| field);
+    let mut path = field.split('.').map(String::from).collect::<Vec<_>>();
+    path.pop();
+    path
+}
+
+// alias.column op :placeholder
+fn compare(column: String,
+           op: &str,
+           alias: &str,
+           field: &str,
+           value: &Value,
+           params: &mut Vec<(String, Value)>)
+           -> String {
+    format!("{} {} {}", column, op, bind(alias, field, value, params))
+}
+
+fn join_conds(conds: &Vec<Cond>,
+              alias: &str,
+              meta: &EntityMeta,
+              orm_meta: &OrmMeta,
+              scope: &Scope,
+              sep: &str,
+              params: &mut Vec<(String, Value)>)
+              -> String {
+    let sql = conds.iter()
+        .map(|cond| cond.to_sql_in(alias, meta, orm_meta, scope, params))
+        .collect::<Vec<_>>()
+        .join(sep);
+    format!("({})", sql)
 }
 
 impl Item {
-    fn to_sql(&self, alias: &str) -> String {
+    fn get_field(&self) -> Option<&String> {
+        match self {
+            &Item::Eq(ref field, ..) |
+            &Item::Ne(ref field, ..) |
+            &Item::Gt(ref field, ..) |
+            &Item::Lt(ref field, ..) |
+            &Item::Ge(ref field, ..) |
+            &Item::Le(ref field, ..) |
+            &Item::In(ref field, ..) |
+            &Item::NotIn(ref field, ..) |
+            &Item::Between(ref field, ..) |
+            &Item::Like(ref field, ..) |
+            &Item::NotLike(ref field, ..) |
+            &Item::Null(ref field) |
+            &Item::NotNull(ref field) |
+            &Item::InSelect(ref field, ..) => Some(field),
+            &Item::Id(..) |
+            &Item::Exists(..) |
+            &Item::NotExists(..) |
+            &Item::And(..) |
+            &Item::Or(..) |
+            &Item::Not(..) => None,
+        }
+    }
+    fn get_paths(&self) -> Vec<Vec<String>> {
+        match self {
+            &Item::And(ref conds) |
+            &Item::Or(ref conds) => conds.iter().flat_map(Cond::get_paths).collect(),
+            &Item::Not(ref cond) => cond.get_paths(),
+            _ => {
+                self.get_field()
+                    .into_iter()
+                    .map(|field| get_expr_path(field))
+                    .filter(|path| path.len() > 0)
+                    .collect()
+            }
+        }
+    }
+    fn check(&self,
+             meta: &EntityMeta,
+             orm_meta: &OrmMeta,
+             scope: &Scope,
+             aggregate: bool)
+             -> Result<(), CondError> {
         match self {
-            &Item::Id(..) => format!("{}.id = :{}", alias, concat(alias, "id")),
-            &Item::Eq(ref field, ..) => format!("{}.{} = :{}", alias, field, concat(alias, field)),
-            &Item::Ne(ref field, ..) => format!("{}.{} <> :{}", alias, field, concat(alias, field)),
-            &Item::Gt(ref field, ..) => format!("{}.{} > :{}", alias, field, concat(alias, field)),
-            &Item::Lt(ref field, ..) => format!("{}.{} < :{}", alias, field, concat(alias, field)),
-            &Item::Null(ref field) => format!("{}.{} IS NULL", alias, field),
-            &Item::NotNull(ref field) => format!("{}.{} IS NOT NULL", alias, field),
+            &Item::And(ref conds) |
+            &Item::Or(ref conds) => {
+                conds.iter().fold(Ok(()), |acc, cond| {
+                    acc.and_then(|_| cond.check_ex(meta, orm_meta, scope, aggregate))
+                })
+            }
+            &Item::Not(ref cond) => cond.check_ex(meta, orm_meta, scope, aggregate),
+            &Item::Exists(ref select) |
+            &Item::NotExists(ref select) => select.check_subquery(meta),
+            &Item::InSelect(ref field, ref select) => {
+                try!(resolve_in("", meta, orm_meta, scope, field));
+                select.check_subquery(meta)
+            }
+            _ if aggregate => {
+                self.get_field().map_or(Ok(()), |field| {
+                    resolve_expr("", meta, orm_meta, scope, field).map(|_| ())
+                })
+            }
+            _ => {
+                self.get_field().map_or(Ok(()), |field| {
+                    resolve_in("", meta, orm_meta, scope, field).map(|_| ())
+                })
+            }
         }
     }
-    fn to_params(&self, alias: &str) -> Vec<(String, Value)> {
+    fn to_sql(&self,
+              alias: &str,
+              meta: &EntityMeta,
+              orm_meta: &OrmMeta,
+              scope: &Scope,
+              params: &mut Vec<(String, Value)>)
+              -> String {
+        let column = |field: &str| column(alias, meta, orm_meta, scope, field);
         match self {
-            &Item::Id(ref value) => vec![(concat(alias, "id"), value.clone())],
-            &Item::Eq(ref field, ref value) |
-            &Item::Ne(ref field, ref value) |
-            &Item::Gt(ref field, ref value) |
-            &Item::Lt(ref field, ref value) => vec![(concat(alias, field), value.clone())],
-            &Item::Null(..) |
-            &Item::NotNull(..) => Vec::new(),
+            &Item::Id(ref value) => compare(column("id"), "=", alias, "id", value, params),
+            &Item::Eq(ref field, ref value) => {
+                compare(column(field), "=", alias, field, value, params)
+            }
+            &Item::Ne(ref field, ref value) => {
+                compare(column(field), "<>", alias, field, value, params)
+            }
+            &Item::Gt(ref field, ref value) => {
+                compare(column(field), ">", alias, field, value, params)
+            }
+            &Item::Lt(ref field, ref value) => {
+                compare(column(field), "<", alias, field, value, params)
+            }
+            &Item::Ge(ref field, ref value) => {
+                compare(column(field), ">=", alias, field, value, params)
+            }
+            &Item::Le(ref field, ref value) => {
+                compare(column(field), "<=", alias, field, value, params)
+            }
+            &Item::Like(ref field, ref value) => {
+                compare(column(field), "LIKE", alias, field, value, params)
+            }
+            &Item::NotLike(ref field, ref value) => {
+                compare(column(field), "NOT LIKE", alias, field, value, params)
+            }
+            // 空列表: IN恒为假, NOT IN恒为真
+            &Item::In(_, ref values) if values.len() == 0 => "1 = 0".to_string(),
+            &Item::NotIn(_, ref values) if values.len() == 0 => "1 = 1".to_string(),
+            &Item::In(ref field, ref values) => {
+                let list = bind_list(alias, field, values, params);
+                format!("{} IN ({})", column(field), list)
+            }
+            &Item::NotIn(ref field, ref values) => {
+                let list = bind_list(alias, field, values, params);
+                format!("{} NOT IN ({})", column(field), list)
+            }
+            &Item::Between(ref field, ref lo, ref hi) => {
+                let lo = bind(alias, field, lo, params);
+                let hi = bind(alias, field, hi, params);
+                format!("{} BETWEEN {} AND {}", column(field), lo, hi)
+            }
+            &Item::Null(ref field) => format!("{} IS NULL", column(field)),
+            &Item::NotNull(ref field) => format!("{} IS NOT NULL", column(field)),
+            &Item::And(ref conds) if conds.len() == 0 => "1 = 1".to_string(),
+            &Item::Or(ref conds) if conds.len() == 0 => "1 = 0".to_string(),
+            &Item::And(ref conds) => {
+                join_conds(conds, alias, meta, orm_meta, scope, " AND ", params)
+            }
+            &Item::Or(ref conds) => join_conds(conds, alias, meta, orm_meta, scope, " OR ", params),
+            &Item::Not(ref cond) => {
+                format!("NOT ({})", cond.to_sql_in(alias, meta, orm_meta, scope, params))
+            }
+            &Item::Exists(ref select) => {
+                format!("EXISTS ({})", select.get_subquery_sql(alias, meta, false, params))
+            }
+            &Item::NotExists(ref select) => {
+                format!("NOT EXISTS ({})",
+                        select.get_subquery_sql(alias, meta, false, params))
+            }
+            &Item::InSelect(ref field, ref select) => {
+                let sql = select.get_subquery_sql(alias, meta, true, params);
+                format!("{} IN ({})", column(field), sql)
+            }
         }
     }
 }
@@ -227,10 +817,42 @@ impl JoinCond {
     //     self
     // }
 
-    pub fn to_sql(&self, a1: &str, a2: &str) -> String {
+    // 左边的字段属于a1的表, 右边的字段属于a2的表, "alias.field"引用scope中的表
+    pub fn check(&self,
+                 a1: &str,
+                 m1: &EntityMeta,
+                 a2: &str,
+                 m2: &EntityMeta,
+                 orm_meta: &OrmMeta,
+                 scope: &Scope)
+                 -> Result<(), CondError> {
+        for item in self.items.iter() {
+            let (f1, f2) = item.get_fields();
+            try!(join_column(a1, m1, orm_meta, scope, f1));
+            try!(join_column(a2, m2, orm_meta, scope, f2));
+        }
+        Ok(())
+    }
+    pub fn to_sql(&self,
+                  a1: &str,
+                  m1: &EntityMeta,
+                  a2: &str,
+                  m2: &EntityMeta,
+                  orm_meta: &OrmMeta,
+                  scope: &Scope)
+                  -> String {
+        let column = |alias: &str, meta: &EntityMeta, field: &str| {
+            let res = join_column(alias, meta, orm_meta, scope, field);
+            // 生成sql前已经检查过
+            debug_assert!(res.is_ok(), "{:?}", res);
+            res.unwrap_or_else(|_| format!("{}.{}", alias, field))
+        };
         self.items
             .iter()
-            .map(|item| item.to_sql(a1, a2))
+            .map(|item| {
+                let (f1, f2) = item.get_fields();
+                item.to_sql(&column(a1, m1, f1), &column(a2, m2, f2))
+            })
             .collect::<Vec<_>>()
             .join(" AND ")
     }
@@ -253,13 +875,41 @@ enum JoinItem {
                          * LtV(String, Value), */
 }
 
+// "field"为alias的表的字段, "alias.field"引用scope中任意别名的表
+// JOIN的条件中没有路径的表, 所以第一段总是按别名处理, 与同名的字段无关
+fn join_column(alias: &str,
+               meta: &EntityMeta,
+               orm_meta: &OrmMeta,
+               scope: &Scope,
+               field: &str)
+               -> Result<String, CondError> {
+    let mut parts = field.splitn(2, '.');
+    let first = parts.next().unwrap();
+    let (alias, meta, field) = match (parts.next(), scope.get(first)) {
+        (None, _) => (alias, meta, field),
+        (Some(rest), Some(b_meta)) if !rest.contains('.') => (first, *b_meta, rest),
+        _ => return Err(CondError::PathInJoinOn(meta.entity.clone(), field.to_string())),
+    };
+    resolve(alias, meta, orm_meta, field)
+        .map(|(alias, _, field_meta)| format!("{}.{}", alias, field_meta.get_column_name()))
+}
+
 impl JoinItem {
-    fn to_sql(&self, a1: &str, a2: &str) -> String {
+    fn get_fields(&self) -> (&str, &str) {
+        match self {
+            &JoinItem::Eq(ref f1, ref f2) |
+            &JoinItem::Ne(ref f1, ref f2) |
+            &JoinItem::Gt(ref f1, ref f2) |
+            &JoinItem::Lt(ref f1, ref f2) => (f1, f2),
+        }
+    }
+    // c1, c2为已经解析好的"alias.column"
+    fn to_sql(&self, c1: &str, c2: &str) -> String {
         match self {
-            &JoinItem::Eq(ref f1, ref f2) => format!("{}.{} = {}.{}", a1, f1, a2, f2),
-            &JoinItem::Ne(ref f1, ref f2) => format!("{}.{} <> {}.{}", a1, f1, a2, f2),
-            &JoinItem::Gt(ref f1, ref f2) => format!("{}.{} > {}.{}", a1, f1, a2, f2),
-            &JoinItem::Lt(ref f1, ref f2) => format!("{}.{} < {}.{}", a1, f1, a2, f2),
+            &JoinItem::Eq(..) => format!("{} = {}", c1, c2),
+            &JoinItem::Ne(..) => format!("{} <> {}", c1, c2),
+            &JoinItem::Gt(..) => format!("{} > {}", c1, c2),
+            &JoinItem::Lt(..) => format!("{} < {}", c1, c2),
             // &JoinItem::EqV(ref f, ..) => format!("{}.{} = :{}", a1, f, concat(a1, f)),
             // &JoinItem::NeV(ref f, ..) => format!("{}.{} <> :{}", a1, f, concat(a1, f)),
             // &JoinItem::GtV(ref f, ..) => format!("{}.{} > :{}", a1, f, concat(a1, f)),
@@ -9,8 +9,11 @@ use meta::OrmMeta;
 use entity::Entity;
 use insert::Insert;
 use select::Select;
+use select::Page;
+use execute::Execute;
+use transaction::Transaction;
 use table;
-// use session::Session;
+use session::Session;
 
 pub struct Db {
     pool: Pool,
@@ -49,6 +52,13 @@ impl Db {
         let insert = Insert::default::<E>();
         insert.execute(&mut self.get_conn(), entity)
     }
+    pub fn execute<E>(&self, execute: &Execute, entity: &E) -> Result<u64, Error>
+        where E: Entity
+    {
+        execute.execute(&mut self.get_conn(), entity)
+    }
+    #[deprecated(note = "rows of joined entities are wrapped as E, use join2 instead")]
+    #[allow(deprecated)]
     pub fn query_ex<E>(&self, select: &Select<E>) -> Result<Vec<Vec<E>>, Error>
         where E: Entity
     {
@@ -59,6 +69,25 @@ impl Db {
     {
         select.query(&mut self.get_conn())
     }
+    pub fn page<E>(&self,
+                   select: &Select<E>,
+                   page_no: u64,
+                   page_size: u64)
+                   -> Result<Page<E>, Error>
+        where E: Entity
+    {
+        select.page(&mut self.get_conn(), page_no, page_size)
+    }
+    pub fn open_session(&self) -> Session {
+        Session::new(self.get_conn())
+    }
+    pub fn transaction<F, R>(&self, f: F) -> Result<R, Error>
+        where F: FnOnce(&Transaction) -> Result<R, Error>
+    {
+        let conn = try!(self.pool.start_transaction(false, None, None));
+        log!("START TRANSACTION");
+        Transaction::new(conn, self.orm_meta).guard(f)
+    }
     // fn session_guard<F, R>(&self, f: F) -> R
     //     where F: Fn(&Session) -> R
     // {
@@ -67,10 +96,6 @@ impl Db {
     //     session.close();
     //     res
     // }
-    // pub fn open_session(&self) -> Session {
-    //     let conn = self.pool.get_conn();
-    //     Session::new(conn.unwrap())
-    // }
     // pub fn insert<E: Entity>(&self, entity: &E) -> Result<(), Error> {
     //     self.session_guard(|session| session.insert(entity))
     // }
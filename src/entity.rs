@@ -6,6 +6,7 @@ use std::collections::HashSet;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::fmt;
+use std::io;
 use std::hash::Hash;
 use std::hash::Hasher;
 
@@ -21,6 +22,11 @@ use meta::EntityMeta;
 use meta::FieldMeta;
 use meta::Cascade;
 use value::FieldValue;
+use session::Session;
+use session::WeakSession;
+use session::SessionStatus;
+use select::SelectImpl;
+use cond::Cond;
 
 pub type EntityInnerPointer = Rc<RefCell<EntityInner>>;
 
@@ -28,8 +34,11 @@ pub struct EntityInner {
     pub orm_meta: &'static OrmMeta,
     pub meta: &'static EntityMeta,
     pub field_map: HashMap<String, FieldValue>,
+    pub origin_map: HashMap<String, Option<Value>>,
+    pub dirty_set: HashSet<String>,
 
-    pub cascade: Option<Cascade>, /* pub session: Option<Session>, // pub cache: Vec<(String, EntityInnerPointer)>, */
+    pub cascade: Option<Cascade>,
+    pub session: Option<WeakSession>,
 }
 
 // 和字段编辑相关
@@ -39,7 +48,10 @@ impl EntityInner {
             orm_meta: orm_meta,
             meta: meta,
             field_map: HashMap::new(),
-            cascade: None, // session: None, // cache: Vec::new(),
+            origin_map: HashMap::new(),
+            dirty_set: HashSet::new(),
+            cascade: None,
+            session: None,
         }
     }
     pub fn default(meta: &'static EntityMeta, orm_meta: &'static OrmMeta) -> EntityInner {
@@ -56,7 +68,10 @@ impl EntityInner {
             orm_meta: orm_meta,
             meta: meta,
             field_map: field_map,
-            cascade: None, // session: None, // cache: Vec::new(),
+            origin_map: HashMap::new(),
+            dirty_set: HashSet::new(),
+            cascade: None,
+            session: None,
         }
     }
     pub fn new_pointer(meta: &'static EntityMeta,
@@ -89,32 +104,70 @@ impl EntityInner {
     pub fn get_value<V>(&self, field: &str) -> V
         where V: FromValue
     {
-        let v = self.field_map.get(field).expect(expect!().as_ref()).as_value();
+        if !self.is_loaded(field) {
+            panic!("Field [{}.{}] Not Loaded", self.meta.entity, field);
+        }
+        let v = self.field_map.get(field).unwrap().as_value();
         value::from_value(v)
     }
+    // 没有加载的字段不在field_map中, 加载了但为NULL的字段值为Value::NULL
+    pub fn is_loaded(&self, field: &str) -> bool {
+        self.field_map.contains_key(field)
+    }
     pub fn set_value<V>(&mut self, field: &str, value: V)
         where Value: From<V>
     {
         let v = Value::from(value);
-        let field_value = FieldValue::from(v);
-        self.field_map.insert(field.to_string(), field_value);
+        self.set_field_value(field, v);
     }
     pub fn set_value_null(&mut self, field: &str) {
-        self.field_map.insert(field.to_string(), FieldValue::null());
+        self.set_field_value(field, Value::NULL);
     }
     pub fn is_value_null(&self, field: &str) -> bool {
         self.field_map.get(field).map_or(false, |v| v.as_value() == Value::NULL)
     }
 }
 
+// Dirty
+impl EntityInner {
+    // 所有对非refer字段的修改都要经过这里，以记录原始值和脏字段
+    pub fn set_field_value(&mut self, field: &str, value: Value) {
+        if !self.origin_map.contains_key(field) {
+            let origin = self.field_map.get(field).map(|v| v.as_value());
+            self.origin_map.insert(field.to_string(), origin);
+        }
+        let unchanged = self.origin_map.get(field).unwrap().as_ref() == Some(&value);
+        if unchanged {
+            self.dirty_set.remove(field);
+        } else {
+            self.dirty_set.insert(field.to_string());
+        }
+        self.field_map.insert(field.to_string(), FieldValue::from(value));
+    }
+    pub fn is_dirty(&self) -> bool {
+        !self.dirty_set.is_empty()
+    }
+    pub fn dirty_fields(&self) -> Vec<String> {
+        self.meta
+            .field_vec
+            .iter()
+            .filter(|field| self.dirty_set.contains(field.as_str()))
+            .map(|field| field.to_string())
+            .collect()
+    }
+    pub fn reset_dirty(&mut self) {
+        self.origin_map.clear();
+        self.dirty_set.clear();
+    }
+}
+
 // Entity
 impl EntityInner {
     pub fn get_entity(&self, field: &str) -> Option<EntityInnerPointer> {
-        let opt = self.field_map.get(field);
-        if opt.is_some() {
-            return opt.unwrap().as_entity();
+        match self.field_map.get(field) {
+            Some(v) => v.as_entity(),
+            None => panic!("Field [{}.{}] Not Loaded", self.meta.entity, field),
         }
-        unreachable!();
     }
     pub fn set_entity(&mut self, field: &str, opt: Option<EntityInnerPointer>) {
         match self.meta.field_map.get(field).expect(expect!().as_ref()) {
@@ -137,15 +190,15 @@ impl EntityInner {
         let (left, right) = field_meta.get_refer_lr();
         if opt.is_none() {
             // a.b_id = null;
-            a.field_map.insert(left, FieldValue::null());
+            a.set_field_value(&left, Value::NULL);
         } else {
             // a.b_id = b.id;
             let b_rc = opt.unwrap();
             let b_id = b_rc.borrow()
                 .field_map
                 .get(&right)
-                .map_or(FieldValue::null(), |v| v.clone());
-            a.field_map.insert(left, b_id);
+                .map_or(Value::NULL, |v| v.as_value());
+            a.set_field_value(&left, b_id);
         }
     }
     fn set_entity_one_one(&mut self, field: &str, opt: Option<EntityInnerPointer>) {
@@ -153,17 +206,17 @@ impl EntityInner {
         let field_meta = a.meta.field_map.get(field).expect(expect!().as_ref());
         let (left, right) = field_meta.get_refer_lr();
 
-        let old_b = a.get_entity(field);
-        if opt.is_some() {
-            // b.a_id = a_id
-            let b_rc = opt.unwrap();
-            let b_id = b_rc.borrow().field_map.get(&right).map_or(Value::NULL, |v| v.as_value());
-            a.field_map.insert(left, FieldValue::from(b_id));
-        }
+        let old_b = a.field_map.get(field).and_then(|v| v.as_entity());
         if old_b.is_some() {
             // old_b.a_id = NULL;
             let old_b = old_b.unwrap();
-            old_b.borrow_mut().field_map.insert(right.clone(), FieldValue::null());
+            old_b.borrow_mut().set_field_value(&right, Value::NULL);
+        }
+        if opt.is_some() {
+            // b.a_id = a_id
+            let b_rc = opt.unwrap();
+            let a_id = a.field_map.get(&left).map_or(Value::NULL, |v| v.as_value());
+            b_rc.borrow_mut().set_field_value(&right, a_id);
         }
     }
 }
@@ -171,27 +224,26 @@ impl EntityInner {
 // Vec
 impl EntityInner {
     pub fn get_vec(&self, field: &str) -> Vec<EntityInnerPointer> {
-        let opt = self.field_map.get(field);
-        if opt.is_some() {
-            return opt.unwrap().as_vec();
+        match self.field_map.get(field) {
+            Some(v) => v.as_vec(),
+            None => panic!("Field [{}.{}] Not Loaded", self.meta.entity, field),
         }
-        unreachable!();
     }
     pub fn set_vec(&mut self, field: &str, vec: Vec<EntityInnerPointer>) {
         let a = self;
         let field_meta = a.meta.field_map.get(field).expect(expect!().as_ref());
         let (left, right) = field_meta.get_refer_lr();
 
-        // 解绑old_vec, old_b.a_id = NULL;
-        let old_vec = a.get_vec(field);
+        // 解绑old_vec, old_b.a_id = NULL; 没有加载时按空处理
+        let old_vec = a.field_map.get(field).map_or(Vec::new(), |v| v.as_vec());
         for old_b_rc in old_vec.iter() {
-            old_b_rc.borrow_mut().field_map.insert(right.to_string(), FieldValue::null());
+            old_b_rc.borrow_mut().set_field_value(&right, Value::NULL);
         }
 
         // 绑定vec, b.a_id = a_id
-        let a_id = a.field_map.get(&left).map_or(FieldValue::null(), |v| v.clone());
+        let a_id = a.field_map.get(&left).map_or(Value::NULL, |v| v.as_value());
         for b_rc in vec.iter() {
-            b_rc.borrow_mut().field_map.insert(right.to_string(), a_id.clone());
+            b_rc.borrow_mut().set_field_value(&right, a_id.clone());
         }
 
         // a.b = b
@@ -203,78 +255,87 @@ impl EntityInner {
 }
 
 // 和session相关
-// impl EntityInner {
-// fn need_lazy_load(&self) -> bool {
-//     // 以下都是没有查到的情况
-//     if self.session.is_none() {
-//         // 没有session，属于临时对象，不进行懒加载
-//         return false;
-//     }
-//     // 以下为有session，即非临时对象的情况
-//     let session = self.session.as_ref().unwrap();
-//     if session.status() == SessionStatus::Closed {
-//         // 游离态,抛异常
-//         panic!("Can't Call Set/Get In Detached Status");
-//     }
-//     if session.status() == SessionStatus::Normal {
-//         // 最常见的情况，正常的lazy load的情况
-//         return true;
-//     }
-//     // 未考虑到的情况
-//     unreachable!();
-// }
-// fn push_cache(&self, rc: EntityInnerPointer) {
-//     // a和b有一个是临时态都不需要做这项操作
-//     if self.session.is_none() || rc.borrow().session.is_none() {
-//         return;
-//     }
-//     let session = self.session.as_ref().unwrap();
-//     match session.status() {
-//         SessionStatus::Closed => unreachable!(), // 异常情况
-//         SessionStatus::Select => unreachable!(), // 目前的情况不应该出现
-//         SessionStatus::Normal => session.push_cache(rc), // 在session内进行操作
-//         SessionStatus::Insert => session.push_cache(rc), // 操作完成后的级联更新
-//         SessionStatus::Update => session.push_cache(rc), // 操作完成后的级联更新
-//         SessionStatus::Delete => session.push_cache(rc), // 操作完成后的级联更新
-//     }
-// }
-// fn ensure_session_not_closed(&self) {
-//     // 游离态
-//     if self.session.is_some() &&
-//        self.session.as_ref().unwrap().status() == SessionStatus::Closed {
-//         panic!("Session Is Closed");
-//     }
-// }
-// pub fn set_session(&mut self, session: Session) {
-// self.session = Some(session);
-// }
-// pub fn set_session_recur(&mut self, session: Session) {
-//     self.set_session(session.clone());
-//     for (_, rc) in self.pointer_map.iter() {
-//         if rc.is_some() {
-//             rc.as_ref().unwrap().borrow_mut().set_session_recur(session.clone());
-//         }
-//     }
-//     for (_, rc) in self.one_one_map.iter() {
-//         if rc.is_some() {
-//             rc.as_ref().unwrap().borrow_mut().set_session_recur(session.clone());
-//         }
-//     }
-//     for (_, vec) in self.one_many_map.iter() {
-//         for rc in vec.iter() {
-//             rc.borrow_mut().set_session_recur(session.clone());
-//         }
-//     }
-//     for (_, vec) in self.many_many_map.iter() {
-//         for &(_, ref rc) in vec.iter() {
-//             rc.borrow_mut().set_session_recur(session.clone());
-//         }
-//     }
-// }
-// pub fn clear_session(&mut self) {
-//     self.session = None;
-// }
-// }
+impl EntityInner {
+    fn need_lazy_load(&self, field: &str) -> Result<bool, Error> {
+        if self.field_map.contains_key(field) {
+            // 已经加载过了
+            return Ok(false);
+        }
+        // 以下都是没有查到的情况
+        if self.session.is_none() {
+            // 没有session，属于临时对象，不进行懒加载
+            return Ok(false);
+        }
+        // 以下为有session，即非临时对象的情况
+        let entity = &self.meta.entity;
+        let session = match self.get_session() {
+            Some(session) => session,
+            None => {
+                // session已经被释放, 同样是游离态
+                let msg = format!("Can't Load Field [{}.{}] In Detached Status", entity, field);
+                return Err(Error::IoError(io::Error::new(io::ErrorKind::NotConnected, msg)));
+            }
+        };
+        if session.status() == SessionStatus::Closed {
+            // 游离态,返回错误
+            let msg = format!("Can't Load Field [{}.{}] In Detached Status", entity, field);
+            return Err(Error::IoError(io::Error::new(io::ErrorKind::NotConnected, msg)));
+        }
+        let field_meta = self.meta.field_map.get(field).expect(&expect!());
+        if !field_meta.is_fetch_lazy() {
+            // eager的关系查询时就应该加载, 没有加载说明查询时去掉了
+            let msg = format!("Field [{}.{}] Is Not Lazy And Not Loaded", entity, field);
+            return Err(Error::IoError(io::Error::new(io::ErrorKind::InvalidInput, msg)));
+        }
+        if session.status() != SessionStatus::Normal {
+            // 查询或者flush的过程中不能再使用session的连接
+            let msg = format!("Can't Load Field [{}.{}] In {:?} Status",
+                              entity,
+                              field,
+                              session.status());
+            return Err(Error::IoError(io::Error::new(io::ErrorKind::InvalidInput, msg)));
+        }
+        // 最常见的情况，正常的lazy load的情况
+        Ok(true)
+    }
+    // 通过session的连接加载关系字段, b.right = a.left
+    pub fn lazy_load(a_rc: &EntityInnerPointer, field: &str) -> Result<(), Error> {
+        if !try!(a_rc.borrow().need_lazy_load(field)) {
+            return Ok(());
+        }
+        let (session, a_meta, orm_meta) = {
+            let a = a_rc.borrow();
+            (a.get_session().unwrap(), a.meta, a.orm_meta)
+        };
+        let a_b_meta = a_meta.field_map.get(field).expect(&expect!());
+        let b_meta = orm_meta.entity_map.get(&a_b_meta.get_refer_entity()).expect(&expect!());
+        let (left, right) = a_b_meta.get_refer_lr();
+        let a_id = a_rc.borrow().field_map.get(&left).map_or(Value::NULL, |v| v.as_value());
+        let vec = match a_id {
+            Value::NULL => Vec::new(),
+            _ => {
+                let mut select = SelectImpl::from_meta(b_meta, orm_meta);
+                select.wher(&Cond::by_eq(&right, a_id));
+                try!(session.query_inner(&select))
+            }
+        };
+        let field_value = match a_b_meta.is_refer_one_many() {
+            true => FieldValue::from(vec),
+            false => FieldValue::from(vec.into_iter().next()),
+        };
+        a_rc.borrow_mut().field_map.insert(field.to_string(), field_value);
+        Ok(())
+    }
+    pub fn set_session(&mut self, session: &Session) {
+        self.session = Some(session.downgrade());
+    }
+    pub fn clear_session(&mut self) {
+        self.session = None;
+    }
+    pub fn get_session(&self) -> Option<Session> {
+        self.session.as_ref().and_then(WeakSession::upgrade)
+    }
+}
 
 impl EntityInner {
     pub fn to_json(&self) -> String {
@@ -349,6 +410,17 @@ pub trait Entity {
         cb(&mut inner)
     }
 
+    fn is_loaded(&self, field: &str) -> bool {
+        self.do_inner(|inner| inner.is_loaded(field))
+    }
+    // 显式加载关系字段, 不能加载时(如游离态)返回错误, getter中会panic
+    fn load(&self, field: &str) -> Result<(), Error> {
+        EntityInner::lazy_load(&self.inner(), field)
+    }
+    fn inner_lazy_load(&self, field: &str) {
+        self.load(field).unwrap_or_else(|err| panic!("{}", err));
+    }
+
     fn inner_set_value<V>(&self, field: &str, value: V)
         where Value: From<V>
     {
@@ -374,27 +446,33 @@ pub trait Entity {
     fn inner_get_entity<E>(&self, field: &str) -> E
         where E: Entity
     {
+        self.inner_lazy_load(field);
         self.do_inner(|inner| E::from_inner(inner.get_entity(field).unwrap()))
     }
     fn inner_set_entity_null(&self, field: &str) {
         self.do_inner_mut(|mut inner| inner.set_entity(field, None))
     }
     fn inner_is_entity_null(&self, field: &str) -> bool {
+        self.inner_lazy_load(field);
         self.do_inner(|inner| inner.get_entity(field).is_none())
     }
 
     fn inner_set_vec<E>(&self, field: &str, vec: Vec<E>)
         where E: Entity
     {
+        // 先加载原来的集合才能解绑, 不能加载时(如游离态)当作空集合
+        let _ = self.load(field);
         let vec = vec.iter().map(E::inner).collect::<Vec<_>>();
         self.do_inner_mut(|mut inner| inner.set_vec(field, vec))
     }
     fn inner_get_vec<E>(&self, field: &str) -> Vec<E>
         where E: Entity
     {
+        self.inner_lazy_load(field);
         self.do_inner(|inner| inner.get_vec(field).into_iter().map(E::from_inner).collect())
     }
     fn inner_is_vec_null(&self, field: &str) -> bool {
+        self.inner_lazy_load(field);
         self.do_inner(|inner| inner.is_vec_null(field))
     }
 }
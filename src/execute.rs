@@ -1,10 +1,12 @@
 use meta::Cascade;
+use meta::FieldMeta;
 use entity::Entity;
 use entity::EntityInner;
 use entity::EntityInnerPointer;
 
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::io;
 
 use mysql::Error;
 use mysql::Value;
@@ -15,6 +17,18 @@ use value::FieldValue;
 // Execute::insert::<E>().update("sdf")
 // Execute::insert::<E>().update(rc)
 
+// 多次执行共用的已处理对象, 如session一次flush中的所有操作
+// 没有从lib导出, 只能在crate内部创建
+pub struct ExecuteSet {
+    set: HashSet<u64>,
+}
+
+impl ExecuteSet {
+    pub fn new() -> Self {
+        ExecuteSet { set: HashSet::new() }
+    }
+}
+
 #[derive(Debug)]
 pub struct Execute {
     cascade: Cascade,
@@ -24,26 +38,26 @@ pub struct Execute {
 
 impl Execute {
     pub fn insert() -> Self {
-        Execute {
-            cascade: Cascade::Insert,
-            field_withs: Vec::new(),
-            entity_withs: HashMap::new(),
-        }
+        Self::from_cascade(Cascade::Insert)
     }
     pub fn update() -> Self {
-        Execute {
-            cascade: Cascade::Update,
-            field_withs: Vec::new(),
-            entity_withs: HashMap::new(),
-        }
+        Self::from_cascade(Cascade::Update)
     }
     pub fn delete() -> Self {
+        Self::from_cascade(Cascade::Delete)
+    }
+    fn from_cascade(cascade: Cascade) -> Self {
         Execute {
-            cascade: Cascade::Delete,
+            cascade: cascade,
             field_withs: Vec::new(),
             entity_withs: HashMap::new(),
         }
     }
+    pub fn with(&mut self, field: &str) -> &mut Execute {
+        let execute = Execute::from_cascade(self.cascade.clone());
+        self.field_withs.push((field.to_string(), execute));
+        &mut self.field_withs.last_mut().unwrap().1
+    }
 }
 
 impl Execute {
@@ -58,6 +72,15 @@ impl Execute {
     {
         self.execute_impl(conn, rc, &mut HashSet::new())
     }
+    pub fn execute_in<C>(&self,
+                         conn: &mut C,
+                         rc: EntityInnerPointer,
+                         set: &mut ExecuteSet)
+                         -> Result<u64, Error>
+        where C: GenericConnection
+    {
+        self.execute_impl(conn, rc, &mut set.set)
+    }
     fn execute_impl<C>(&self,
                        conn: &mut C,
                        rc: EntityInnerPointer,
@@ -65,15 +88,70 @@ impl Execute {
                        -> Result<u64, Error>
         where C: GenericConnection
     {
+        // 同一个对象在一次执行中只处理一次，防止循环引用
+        let addr = rc.borrow().get_addr();
+        if set.contains(&addr) {
+            return Ok(0);
+        }
+        set.insert(addr);
+        if self.cascade == Cascade::Delete {
+            return self.execute_delete(conn, rc, set);
+        }
         let r1 = try!(self.execute_pointer(conn, rc.clone(), set));
         let r2 = match self.cascade {
             Cascade::Insert => try!(self.execute_insert_self(conn, rc.clone(), set)),
+            Cascade::Update => try!(self.execute_update_self(conn, rc.clone(), set)),
             _ => unreachable!(),
         };
         let r3 = try!(self.execute_one_one(conn, rc.clone(), set));
         let r4 = try!(self.execute_one_many(conn, rc.clone(), set));
         Ok(r1 + r2 + r3 + r4)
     }
+    // 删除顺序与插入相反: 先删引用自己的one_one/one_many, 再删自己, 最后删自己指向的pointer
+    fn execute_delete<C>(&self,
+                         conn: &mut C,
+                         rc: EntityInnerPointer,
+                         set: &mut HashSet<u64>)
+                         -> Result<u64, Error>
+        where C: GenericConnection
+    {
+        let r0 = try!(self.execute_delete_unloaded(conn, rc.clone()));
+        let r1 = try!(self.execute_one_one(conn, rc.clone(), set));
+        let r2 = try!(self.execute_one_many(conn, rc.clone(), set));
+        let r3 = try!(self.execute_delete_self(conn, rc.clone(), set));
+        let r4 = try!(self.execute_pointer(conn, rc.clone(), set));
+        Ok(r0 + r1 + r2 + r3 + r4)
+    }
+    fn has_cascade(&self, field_meta: &FieldMeta) -> bool {
+        match self.cascade {
+            // insert只处理显式with的关系, 与原来的行为一致
+            Cascade::Insert => false,
+            Cascade::Update => field_meta.has_cascade_update(),
+            Cascade::Delete => field_meta.has_cascade_delete(),
+            Cascade::NULL => false,
+        }
+    }
+    // 显式with的字段加上注解了级联的字段，注解的字段使用default执行
+    fn get_field_withs<'a>(&'a self,
+                           rc: &EntityInnerPointer,
+                           default: &'a Execute)
+                           -> Vec<(String, &'a Execute)> {
+        let meta = rc.borrow().meta;
+        meta.field_vec
+            .iter()
+            .filter_map(|field| {
+                let field_meta = meta.field_map.get(field).unwrap();
+                if !field_meta.is_type_refer() {
+                    return None;
+                }
+                match self.field_withs.iter().find(|&&(ref with, _)| with == field) {
+                    Some(&(_, ref execute)) => Some((field.to_string(), execute)),
+                    None if self.has_cascade(field_meta) => Some((field.to_string(), default)),
+                    None => None,
+                }
+            })
+            .collect()
+    }
 }
 
 impl Execute {
@@ -116,11 +194,121 @@ impl Execute {
                     .insert("id".to_string(),
                             FieldValue::from(Value::from(res.last_insert_id())));
             }
+            rc.borrow_mut().reset_dirty();
             res.affected_rows()
         })
     }
 }
 
+impl Execute {
+    fn execute_update_self<C>(&self,
+                              conn: &mut C,
+                              rc: EntityInnerPointer,
+                              set: &mut HashSet<u64>)
+                              -> Result<u64, Error>
+        where C: GenericConnection
+    {
+        if rc.borrow().get_id_u64().is_none() {
+            let msg = "Update Entity Has No Id";
+            return Err(Error::IoError(io::Error::new(io::ErrorKind::InvalidInput, msg)));
+        }
+        let meta = rc.borrow().meta;
+        let table = meta.table.clone();
+        // 只更新修改过的字段
+        let dirty_fields = rc.borrow().dirty_fields();
+        let valid_fields = dirty_fields.iter()
+            .map(|field| meta.field_map.get(field).unwrap())
+            .filter(|field_meta| !field_meta.is_type_id() && !field_meta.is_type_refer())
+            .collect::<Vec<_>>();
+        if valid_fields.len() == 0 {
+            return Ok(0);
+        }
+        let mut params = valid_fields.iter()
+            .map(|field_meta| {
+                let field = field_meta.get_field_name();
+                let value = rc.borrow().field_map.get(&field).expect(&expect!()).as_value();
+                (field, value)
+            })
+            .collect::<Vec<_>>();
+        params.push(("id".to_string(), rc.borrow().get_id_value()));
+        let fields = valid_fields.iter()
+            .map(|field_meta| {
+                format!("`{}` = :{}",
+                        field_meta.get_column_name(),
+                        field_meta.get_field_name())
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = format!("UPDATE `{}` SET {} WHERE `id` = :id", table, fields);
+        log!("{}", sql);
+        log!("{:?}", params);
+        conn.prep_exec(sql, params).map(|res| {
+            rc.borrow_mut().reset_dirty();
+            res.affected_rows()
+        })
+    }
+}
+
+impl Execute {
+    fn execute_delete_self<C>(&self,
+                              conn: &mut C,
+                              rc: EntityInnerPointer,
+                              set: &mut HashSet<u64>)
+                              -> Result<u64, Error>
+        where C: GenericConnection
+    {
+        if rc.borrow().get_id_u64().is_none() {
+            let msg = "Delete Entity Has No Id";
+            return Err(Error::IoError(io::Error::new(io::ErrorKind::InvalidInput, msg)));
+        }
+        let table = rc.borrow().meta.table.clone();
+        let params = vec![("id".to_string(), rc.borrow().get_id_value())];
+        let sql = format!("DELETE FROM `{}` WHERE `id` = :id", table);
+        log!("{}", sql);
+        log!("{:?}", params);
+        conn.prep_exec(sql, params).map(|res| {
+            // 删除之后变为临时对象, 可以重新插入
+            rc.borrow_mut().field_map.insert("id".to_string(), FieldValue::from(Value::NULL));
+            res.affected_rows()
+        })
+    }
+    // 没有加载的one_one/one_many无法逐个删除, 直接按外键删除, b.a_id = a.id
+    // 这些对象上的关系不会再级联删除
+    fn execute_delete_unloaded<C>(&self,
+                                  conn: &mut C,
+                                  rc: EntityInnerPointer)
+                                  -> Result<u64, Error>
+        where C: GenericConnection
+    {
+        let default = Execute::from_cascade(self.cascade.clone());
+        let mut ret = 0;
+        for (field, _) in self.get_field_withs(&rc, &default) {
+            let (table, column, a_id) = {
+                let a = rc.borrow();
+                let a_b_meta = a.meta.field_map.get(&field).unwrap();
+                let is_child = a_b_meta.is_refer_one_one() || a_b_meta.is_refer_one_many();
+                if !is_child || a.field_map.contains_key(&field) {
+                    continue;
+                }
+                let b_meta = a.orm_meta.entity_map.get(&a_b_meta.get_refer_entity()).unwrap();
+                let (left, right) = a_b_meta.get_refer_lr();
+                let a_id = a.field_map.get(&left).map_or(Value::NULL, |v| v.as_value());
+                let column = b_meta.field_map.get(&right).unwrap().get_column_name();
+                (b_meta.table.clone(), column, a_id)
+            };
+            if a_id == Value::NULL {
+                continue;
+            }
+            let params = vec![("a_id".to_string(), a_id)];
+            let sql = format!("DELETE FROM `{}` WHERE `{}` = :a_id", table, column);
+            log!("{}", sql);
+            log!("{:?}", params);
+            ret += try!(conn.prep_exec(sql, params)).affected_rows();
+        }
+        Ok(ret)
+    }
+}
+
 impl Execute {
     fn execute_pointer<C>(&self,
                           conn: &mut C,
@@ -129,9 +317,10 @@ impl Execute {
                           -> Result<u64, Error>
         where C: GenericConnection
     {
-        self.field_withs
-            .iter()
-            .filter_map(|&(ref field, ref execute)| {
+        let default = Execute::from_cascade(self.cascade.clone());
+        let withs = self.get_field_withs(&rc, &default);
+        let res = withs.iter()
+            .filter_map(|&(ref field, execute)| {
                 // 是pointer
                 if !rc.borrow().meta.field_map.get(field).unwrap().is_refer_pointer() {
                     return None;
@@ -150,7 +339,8 @@ impl Execute {
                             }
                         })
                 })
-            }).fold(Ok(0), |acc, (field, execute, b_rc)| {
+            })
+            .fold(Ok(0), |acc, (field, execute, b_rc)| {
                 if acc.is_err() {
                     return acc;
                 }
@@ -170,8 +360,8 @@ impl Execute {
 
                 let acc = acc.unwrap() + res.unwrap();
                 Ok(acc)
-            })
-
+            });
+        res
     }
     fn execute_one_one<C>(&self,
                           conn: &mut C,
@@ -180,9 +370,10 @@ impl Execute {
                           -> Result<u64, Error>
         where C: GenericConnection
     {
-        self.field_withs
-            .iter()
-            .filter_map(|&(ref field, ref execute)| {
+        let default = Execute::from_cascade(self.cascade.clone());
+        let withs = self.get_field_withs(&rc, &default);
+        let res = withs.iter()
+            .filter_map(|&(ref field, execute)| {
                 // 是one_one
                 if !rc.borrow().meta.field_map.get(field).unwrap().is_refer_one_one() {
                     return None;
@@ -222,7 +413,8 @@ impl Execute {
                 }
                 let acc = acc.unwrap() + res.unwrap();
                 Ok(acc)
-            })
+            });
+        res
     }
     fn execute_one_many<C>(&self,
                            conn: &mut C,
@@ -231,9 +423,10 @@ impl Execute {
                            -> Result<u64, Error>
         where C: GenericConnection
     {
-        self.field_withs
-            .iter()
-            .filter_map(|&(ref field, ref execute)| {
+        let default = Execute::from_cascade(self.cascade.clone());
+        let withs = self.get_field_withs(&rc, &default);
+        let res = withs.iter()
+            .filter_map(|&(ref field, execute)| {
                 // one_many
                 if !rc.borrow().meta.field_map.get(field).unwrap().is_refer_one_many() {
                     return None;
@@ -289,6 +482,7 @@ impl Execute {
                     return res;
                 }
                 Ok(acc.unwrap() + res.unwrap())
-            })
+            });
+        res
     }
 }
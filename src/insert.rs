@@ -217,6 +217,7 @@ impl Insert {
                     .insert("id".to_string(),
                             FieldValue::from(Value::from(res.last_insert_id())));
             }
+            rc.borrow_mut().reset_dirty();
             res.affected_rows()
         })
     }
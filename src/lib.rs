@@ -16,13 +16,14 @@ mod visitor;
 mod attr;
 mod entity;
 mod db;
-// mod session;
+mod session;
 mod cond;
 mod value;
 mod insert;
 mod select;
 mod table;
 mod execute;
+mod transaction;
 
 pub mod init;
 pub mod meta;
@@ -38,9 +39,17 @@ pub use mysql::Row;
 pub use db::Db;
 pub use insert::Insert;
 pub use select::Select;
+pub use select::Select2;
+pub use select::Order;
+pub use select::Page;
+pub use select::Projection;
 pub use execute::Execute;
+pub use transaction::Transaction;
+pub use session::Session;
+pub use session::SessionStatus;
 pub use cond::Cond;
 pub use cond::JoinCond;
+pub use cond::CondError;
 // pub use value::FieldValue;
 
 use syntax::codemap::CodeMap;
@@ -395,6 +395,9 @@ impl FieldMeta {
     pub fn is_fetch_eager(&self) -> bool {
         self.get_refer_fetch() == Fetch::Eager
     }
+    pub fn is_fetch_lazy(&self) -> bool {
+        self.get_refer_fetch() == Fetch::Lazy
+    }
 }
 
 impl FieldMeta {
@@ -6,19 +6,31 @@ use entity::EntityInner;
 use entity::EntityInnerPointer;
 use cond::Cond;
 use cond::JoinCond;
+use cond::CondError;
+use cond::Scope;
+use cond;
 use meta::OrmMeta;
 use meta::EntityMeta;
 use meta::FieldMeta;
 use value::FieldValue;
 
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::mem;
+use std::io;
 
 use mysql::Value;
 use mysql::conn::GenericConnection;
 use mysql::Row;
 use mysql::Error;
+use mysql::QueryResult;
+use mysql::from_value;
+use mysql::from_value_opt;
+use mysql::from_row_opt;
+use mysql::prelude::FromValue;
+use mysql::prelude::FromRow;
 
 use std::marker::PhantomData;
 
@@ -32,14 +44,54 @@ pub struct Select<E> {
     imp: SelectImpl,
 }
 
+// page_no从1开始
 #[derive(Debug)]
+pub struct Page<E> {
+    pub items: Vec<E>,
+    pub page_no: u64,
+    pub page_size: u64,
+    pub total: u64,
+    pub page_count: u64,
+    pub has_next: bool,
+}
+
+// 投影到任意结构, fields为要查询的字段、路径或聚合, 顺序与FromRow中读取的顺序一致
+pub trait Projection: FromRow {
+    fn fields() -> Vec<&'static str>;
+}
+
+// 两个entity的join查询, 每行结果为(E1, Option<E2>)
+#[derive(Debug)]
+pub struct Select2<E1, E2> {
+    phantom: PhantomData<(E1, E2)>,
+    imp: SelectImpl,
+    index: usize,
+}
+
+// 单独查询的with: (所在的根或join在flat_select中的序号, 从它到所在对象的字段路径, 字段, select)
+type BatchWith = (usize, Vec<String>, String, SelectImpl);
+
+#[derive(Debug, Clone)]
 pub struct SelectImpl {
     meta: &'static EntityMeta,
     orm_meta: &'static OrmMeta,
     alias: String,
+    named: bool,
     cond: Option<Cond>,
+    filter: Option<Cond>,
     withs: Vec<(String, SelectImpl)>,
     joins: Vec<Join>,
+    eager: bool,
+    hidden: bool,
+    batch: bool,
+    correlation: Option<JoinCond>,
+    orders: Vec<(String, Order)>,
+    limit: Option<u64>,
+    offset: Option<u64>,
+    groups: Vec<String>,
+    having: Option<Cond>,
+    fields: Option<Vec<String>>,
+    scope: Scope,
 }
 
 impl<E> Select<E>
@@ -58,6 +110,42 @@ impl<E> Select<E>
     pub fn with(&mut self, field: &str) -> &mut SelectImpl {
         self.imp.with(field)
     }
+    pub fn no_eager(&mut self) -> &mut SelectImpl {
+        self.imp.no_eager()
+    }
+    pub fn correlate(&mut self, cond: &JoinCond) -> &mut SelectImpl {
+        self.imp.correlate(cond)
+    }
+    pub fn order_by(&mut self, field: &str, order: Order) -> &mut SelectImpl {
+        self.imp.order_by(field, order)
+    }
+    pub fn limit(&mut self, limit: u64) -> &mut SelectImpl {
+        self.imp.limit(limit)
+    }
+    pub fn offset(&mut self, offset: u64) -> &mut SelectImpl {
+        self.imp.offset(offset)
+    }
+    pub fn columns(&mut self, fields: &[&str]) -> &mut SelectImpl {
+        self.imp.columns(fields)
+    }
+    pub fn group_by(&mut self, fields: &[&str]) -> &mut SelectImpl {
+        self.imp.group_by(fields)
+    }
+    pub fn having(&mut self, cond: &Cond) -> &mut SelectImpl {
+        self.imp.having(cond)
+    }
+    pub fn project<C, R>(&self, conn: &mut C, exprs: &[&str]) -> Result<Vec<R>, Error>
+        where C: GenericConnection,
+              R: FromRow
+    {
+        self.imp.project(conn, exprs)
+    }
+    pub fn map_into<C, P>(&self, conn: &mut C) -> Result<Vec<P>, Error>
+        where C: GenericConnection,
+              P: Projection
+    {
+        self.imp.project(conn, &P::fields())
+    }
     pub fn join<Et>(&mut self, cond: &JoinCond) -> &mut Join
         where Et: Entity
     {
@@ -78,11 +166,64 @@ impl<E> Select<E>
     {
         self.imp.outer_join::<Et>(cond)
     }
+    pub fn join_as<Et>(&mut self, alias: &str, cond: &JoinCond) -> &mut Join
+        where Et: Entity
+    {
+        self.imp.join_as::<Et>(alias, cond)
+    }
+    pub fn left_join_as<Et>(&mut self, alias: &str, cond: &JoinCond) -> &mut Join
+        where Et: Entity
+    {
+        self.imp.left_join_as::<Et>(alias, cond)
+    }
+    pub fn right_join_as<Et>(&mut self, alias: &str, cond: &JoinCond) -> &mut Join
+        where Et: Entity
+    {
+        self.imp.right_join_as::<Et>(alias, cond)
+    }
+    pub fn join_rel(&mut self, field: &str) -> &mut Join {
+        self.imp.join_rel(field)
+    }
+    pub fn left_join_rel(&mut self, field: &str) -> &mut Join {
+        self.imp.left_join_rel(field)
+    }
+    pub fn right_join_rel(&mut self, field: &str) -> &mut Join {
+        self.imp.right_join_rel(field)
+    }
+    pub fn join2<Et>(self, cond: &JoinCond) -> Select2<E, Et>
+        where Et: Entity
+    {
+        self.join2_impl(cond, JoinKind::Inner)
+    }
+    pub fn left_join2<Et>(self, cond: &JoinCond) -> Select2<E, Et>
+        where Et: Entity
+    {
+        self.join2_impl(cond, JoinKind::Left)
+    }
+    // 右边没有匹配的根对象时E1为None, 用query_opt读取
+    pub fn right_join2<Et>(self, cond: &JoinCond) -> Select2<E, Et>
+        where Et: Entity
+    {
+        self.join2_impl(cond, JoinKind::Right)
+    }
+    fn join2_impl<Et>(mut self, cond: &JoinCond, kind: JoinKind) -> Select2<E, Et>
+        where Et: Entity
+    {
+        self.imp.join_impl::<Et>(cond, kind);
+        let index = self.imp.joins.len() - 1;
+        Select2 {
+            phantom: PhantomData,
+            imp: self.imp,
+            index: index,
+        }
+    }
     pub fn query<C>(&self, conn: &mut C) -> Result<Vec<E>, Error>
         where C: GenericConnection
     {
         self.imp.query_inner(conn).map(|vec| vec.into_iter().map(E::from_inner).collect())
     }
+    // join的结果也被当作E返回, join其他entity时类型不对, 用join2/left_join2/right_join2代替
+    #[deprecated(note = "rows of joined entities are wrapped as E, use join2 instead")]
     pub fn query_ex<C>(&self, conn: &mut C) -> Result<Vec<Vec<E>>, Error>
         where C: GenericConnection
     {
@@ -92,6 +233,135 @@ impl<E> Select<E>
                 tuple.into_iter().map(|vec| vec.into_iter().map(E::from_inner).collect()).collect()
             })
     }
+    // 流式读取, 不需要把所有结果放在内存里
+    // 按照根对象的id排序, 同一个根对象的行连续出现, 读完一个根对象就返回
+    // 与query展开方式相同, batch的with需要所有根对象读完后才能查询, 不支持
+    pub fn iter<'a, C>(&self, conn: &'a mut C) -> Result<SelectIter<'a, E>, Error>
+        where C: GenericConnection
+    {
+        let (mut select, batches) = try!(self.imp.expand_batch(true));
+        if let Some(&(_, ref path, ref field, _)) = batches.first() {
+            let mut path = path.clone();
+            path.push(field.clone());
+            return Err(Error::from(CondError::BatchInIter(path.join("."))));
+        }
+        select.orders.push(("id".to_string(), Order::Asc));
+        let (sql, params) = select.get_sql_params();
+        log!("{}", sql);
+        log!("\t{:?}", params);
+        let res = match params.len() {
+            0 => conn.prep_exec(sql, ()),
+            _ => conn.prep_exec(sql, params),
+        };
+        let result = try!(res);
+        Ok(SelectIter {
+            phantom: PhantomData,
+            select: select,
+            result: result,
+            map: HashMap::new(),
+            current: None,
+        })
+    }
+    pub fn page<C>(&self,
+                   conn: &mut C,
+                   page_no: u64,
+                   page_size: u64)
+                   -> Result<Page<E>, Error>
+        where C: GenericConnection
+    {
+        if page_no == 0 || page_size == 0 {
+            let msg = "Page No And Page Size Must Start From 1";
+            return Err(Error::IoError(io::Error::new(io::ErrorKind::InvalidInput, msg)));
+        }
+        let total = try!(self.imp.query_count(conn));
+        let mut imp = self.imp.clone();
+        imp.limit(page_size).offset((page_no - 1) * page_size);
+        let items = try!(imp.query_inner(conn)).into_iter().map(E::from_inner).collect();
+        let page_count = (total + page_size - 1) / page_size;
+        Ok(Page {
+            items: items,
+            page_no: page_no,
+            page_size: page_size,
+            total: total,
+            page_count: page_count,
+            has_next: page_no < page_count,
+        })
+    }
+    // 聚合查询不加载对象, 结果为NULL时(如没有数据时的SUM)需要用Option<T>接收
+    pub fn count<C>(&self, conn: &mut C) -> Result<u64, Error>
+        where C: GenericConnection
+    {
+        self.imp.query_count(conn)
+    }
+    pub fn count_distinct<C>(&self, conn: &mut C, field: &str) -> Result<u64, Error>
+        where C: GenericConnection
+    {
+        self.imp.query_aggregate(conn, "COUNT(DISTINCT {})", field)
+    }
+    pub fn sum<C, T>(&self, conn: &mut C, field: &str) -> Result<T, Error>
+        where C: GenericConnection,
+              T: FromValue
+    {
+        self.imp.query_aggregate(conn, "SUM({})", field)
+    }
+    pub fn avg<C, T>(&self, conn: &mut C, field: &str) -> Result<T, Error>
+        where C: GenericConnection,
+              T: FromValue
+    {
+        self.imp.query_aggregate(conn, "AVG({})", field)
+    }
+    pub fn min<C, T>(&self, conn: &mut C, field: &str) -> Result<T, Error>
+        where C: GenericConnection,
+              T: FromValue
+    {
+        self.imp.query_aggregate(conn, "MIN({})", field)
+    }
+    pub fn max<C, T>(&self, conn: &mut C, field: &str) -> Result<T, Error>
+        where C: GenericConnection,
+              T: FromValue
+    {
+        self.imp.query_aggregate(conn, "MAX({})", field)
+    }
+    pub fn get_impl(&self) -> &SelectImpl {
+        &self.imp
+    }
+}
+
+impl<E1, E2> Select2<E1, E2>
+    where E1: Entity,
+          E2: Entity
+{
+    pub fn wher(&mut self, cond: &Cond) -> &mut SelectImpl {
+        self.imp.wher(cond)
+    }
+    pub fn with(&mut self, field: &str) -> &mut SelectImpl {
+        self.imp.with(field)
+    }
+    // E2对应的join, 可以继续设置on/wher/with
+    pub fn get_join(&mut self) -> &mut Join {
+        &mut self.imp.joins[self.index]
+    }
+    // 没有E1的行(right join)被忽略
+    pub fn query<C>(&self, conn: &mut C) -> Result<Vec<(E1, Option<E2>)>, Error>
+        where C: GenericConnection
+    {
+        self.imp.query_pairs(conn, self.index).map(|vec| {
+            vec.into_iter()
+                .filter_map(|(a_rc, b_rc)| {
+                    a_rc.map(|a_rc| (E1::from_inner(a_rc), b_rc.map(E2::from_inner)))
+                })
+                .collect()
+        })
+    }
+    pub fn query_opt<C>(&self, conn: &mut C) -> Result<Vec<(Option<E1>, Option<E2>)>, Error>
+        where C: GenericConnection
+    {
+        self.imp.query_pairs(conn, self.index).map(|vec| {
+            vec.into_iter()
+                .map(|(a_rc, b_rc)| (a_rc.map(E1::from_inner), b_rc.map(E2::from_inner)))
+                .collect()
+        })
+    }
 }
 
 impl SelectImpl {
@@ -100,9 +370,22 @@ impl SelectImpl {
             meta: meta,
             orm_meta: orm_meta,
             alias: meta.alias.clone(),
+            named: false,
             cond: None,
+            filter: None,
             withs: Vec::new(),
             joins: Vec::new(),
+            eager: true,
+            hidden: false,
+            batch: false,
+            correlation: None,
+            orders: Vec::new(),
+            limit: None,
+            offset: None,
+            groups: Vec::new(),
+            having: None,
+            fields: None,
+            scope: HashMap::new(),
         }
     }
     fn from_alias(meta: &'static EntityMeta, orm_meta: &'static OrmMeta, alias: String) -> Self {
@@ -110,9 +393,22 @@ impl SelectImpl {
             meta: meta,
             orm_meta: orm_meta,
             alias: alias,
+            named: false,
             cond: None,
+            filter: None,
             withs: Vec::new(),
             joins: Vec::new(),
+            eager: true,
+            hidden: false,
+            batch: false,
+            correlation: None,
+            orders: Vec::new(),
+            limit: None,
+            offset: None,
+            groups: Vec::new(),
+            having: None,
+            fields: None,
+            scope: HashMap::new(),
         }
     }
 
@@ -122,16 +418,73 @@ impl SelectImpl {
     }
 
     pub fn with(&mut self, field: &str) -> &mut Self {
+        self.with_impl(field, false)
+    }
+    // hidden为true时只JOIN表用于条件过滤, 不查询字段也不填充到对象上
+    fn with_impl(&mut self, field: &str, hidden: bool) -> &mut Self {
         let a = self;
         let field_meta = a.meta.field_map.get(field).expect(&expect!());
         let b_entity = field_meta.get_refer_entity();
         let b_meta = a.orm_meta.entity_map.get(&b_entity).unwrap();
 
         let alias = format!("{}_{}", &a.alias, field);
-        let select = SelectImpl::from_alias(b_meta, a.orm_meta, alias);
+        let mut select = SelectImpl::from_alias(b_meta, a.orm_meta, alias);
+        select.hidden = hidden;
         a.withs.push((field.to_string(), select));
         &mut a.withs.last_mut().unwrap().1
     }
+    // 只能用在with的select上, 根对象或join上使用时查询报错
+    // 条件放在LEFT JOIN的ON中, 只过滤加载的关系, 不过滤外层对象
+    // 没有满足条件的子对象时one_many为空的Vec, 而wher会把外层对象也过滤掉
+    pub fn filter(&mut self, cond: &Cond) -> &mut Self {
+        self.filter = Some(cond.clone());
+        self
+    }
+    // 只在one_many的with上有效, 不再LEFT JOIN, 先查出外层对象, 再用IN单独查询一次
+    // 多个one_many一起加载时可以避免行数相乘, 此时wher只过滤加载的关系, 与filter相同
+    pub fn batch(&mut self) -> &mut Self {
+        self.batch = true;
+        self
+    }
+    // 本次查询不自动加载fetch(eager)的关系
+    pub fn no_eager(&mut self) -> &mut Self {
+        self.eager = false;
+        self
+    }
+    // 可以在with/join的select上调用, 排序按照调用的先后, 根在前子在后
+    pub fn order_by(&mut self, field: &str, order: Order) -> &mut Self {
+        self.orders.push((field.to_string(), order));
+        self
+    }
+    // limit/offset只对根对象有效
+    pub fn limit(&mut self, limit: u64) -> &mut Self {
+        self.limit = Some(limit);
+        self
+    }
+    pub fn offset(&mut self, offset: u64) -> &mut Self {
+        self.offset = Some(offset);
+        self
+    }
+    // 只加载部分字段, id和外键总是会加载, 其余字段为未加载状态(与NULL不同)
+    pub fn columns(&mut self, fields: &[&str]) -> &mut Self {
+        self.fields = Some(fields.iter().map(|field| field.to_string()).collect());
+        self
+    }
+    // group_by/having只在project时有效
+    pub fn group_by(&mut self, fields: &[&str]) -> &mut Self {
+        self.groups = fields.iter().map(|field| field.to_string()).collect();
+        self
+    }
+    // 条件中可以使用聚合, 如Cond::by_gt("count(*)", 1)
+    pub fn having(&mut self, cond: &Cond) -> &mut Self {
+        self.having = Some(cond.clone());
+        self
+    }
+    // 作为子查询时与外层的关联条件, JoinCond左边为外层字段, 右边为子查询字段
+    pub fn correlate(&mut self, cond: &JoinCond) -> &mut Self {
+        self.correlation = Some(cond.clone());
+        self
+    }
     pub fn join<E>(&mut self, cond: &JoinCond) -> &mut Join
         where E: Entity
     {
@@ -152,15 +505,352 @@ impl SelectImpl {
     {
         self.join_impl::<E>(cond, JoinKind::Right)
     }
+    // 指定别名的JOIN, 同一个entity可以JOIN多次, 别名在整个语句中不能重复
+    pub fn join_as<E>(&mut self, alias: &str, cond: &JoinCond) -> &mut Join
+        where E: Entity
+    {
+        self.join_as_impl::<E>(alias, cond, JoinKind::Inner)
+    }
+    pub fn left_join_as<E>(&mut self, alias: &str, cond: &JoinCond) -> &mut Join
+        where E: Entity
+    {
+        self.join_as_impl::<E>(alias, cond, JoinKind::Left)
+    }
+    pub fn right_join_as<E>(&mut self, alias: &str, cond: &JoinCond) -> &mut Join
+        where E: Entity
+    {
+        self.join_as_impl::<E>(alias, cond, JoinKind::Right)
+    }
+    fn join_as_impl<E>(&mut self, alias: &str, cond: &JoinCond, kind: JoinKind) -> &mut Join
+        where E: Entity
+    {
+        let join = self.join_meta(E::meta(), cond, kind);
+        join.select.alias = alias.to_string();
+        join.select.named = true;
+        join
+    }
+    // 沿着已声明的关系JOIN, ON条件由get_refer_lr得到
+    pub fn join_rel(&mut self, field: &str) -> &mut Join {
+        self.join_rel_impl(field, JoinKind::Inner)
+    }
+    pub fn left_join_rel(&mut self, field: &str) -> &mut Join {
+        self.join_rel_impl(field, JoinKind::Left)
+    }
+    pub fn right_join_rel(&mut self, field: &str) -> &mut Join {
+        self.join_rel_impl(field, JoinKind::Right)
+    }
+    // 字段不是关系时先JOIN自己占位, 记录错误, 查询时返回
+    fn join_rel_impl(&mut self, field: &str, kind: JoinKind) -> &mut Join {
+        let a_meta = self.meta;
+        let entity = a_meta.entity.clone();
+        let error = match a_meta.field_map.get(field) {
+            None => Some(CondError::UnknownField(entity, field.to_string())),
+            Some(a_b_meta) if !a_b_meta.is_type_refer() => {
+                Some(CondError::NotReferField(entity, field.to_string()))
+            }
+            _ => None,
+        };
+        if error.is_some() {
+            let join = self.join_meta(a_meta, &JoinCond::by_eq("id", "id"), kind);
+            join.error = error;
+            return join;
+        }
+        let a_b_meta = a_meta.field_map.get(field).unwrap();
+        let b_meta = self.orm_meta.entity_map.get(&a_b_meta.get_refer_entity()).unwrap();
+        let (a_field, b_field) = a_b_meta.get_refer_lr();
+        let a_column = a_meta.field_map.get(&a_field).unwrap().get_column_name();
+        let b_column = b_meta.field_map.get(&b_field).unwrap().get_column_name();
+        let cond = JoinCond::by_eq(&a_column, &b_column);
+        self.join_meta(b_meta, &cond, kind)
+    }
     fn join_impl<E>(&mut self, cond: &JoinCond, kind: JoinKind) -> &mut Join
         where E: Entity
     {
-        let alias = format!("{}_{}", self.alias, E::meta().alias.clone());
-        let select = SelectImpl::from_alias(E::meta(), E::orm_meta(), alias);
+        self.join_meta(E::meta(), cond, kind)
+    }
+    fn join_meta(&mut self,
+                 meta: &'static EntityMeta,
+                 cond: &JoinCond,
+                 kind: JoinKind)
+                 -> &mut Join {
+        let alias = format!("{}_{}", self.alias, meta.alias.clone());
+        let select = SelectImpl::from_alias(meta, self.orm_meta, alias);
         let join = Join::new(kind, cond.clone(), select);
         self.joins.push(join);
         self.joins.last_mut().unwrap()
     }
+    // 查询前展开: 加上fetch(eager)的关系和条件路径需要的JOIN
+    fn expand(&self) -> Result<SelectImpl, CondError> {
+        self.expand_batch(false).map(|(select, _)| select)
+    }
+    // batch为true时把batch的with拿出来单独查询, 否则仍然用LEFT JOIN加载
+    // 别名确定之后再检查条件, 条件中可以引用语句中的其他别名
+    fn expand_batch(&self, batch: bool) -> Result<(SelectImpl, Vec<BatchWith>), CondError> {
+        let mut select = self.clone();
+        if select.eager {
+            select.inner_expand_eager(&mut Vec::new());
+        }
+        let mut batches = Vec::new();
+        if batch {
+            try!(select.detach_batch(&mut 0, &mut batches));
+        }
+        select.inner_expand_paths();
+        try!(select.unique_aliases(&mut HashSet::new()));
+        select.bind_scope();
+        try!(select.check_cond());
+        Ok((select, batches))
+    }
+    // 要在展开路径之前拿出来, 条件中的路径需要的关系重新加一个hidden的with
+    // index按照flat_select的顺序编号: 先是自己, 然后依次是每个join
+    fn detach_batch(&mut self,
+                    index: &mut usize,
+                    batches: &mut Vec<BatchWith>)
+                    -> Result<(), CondError> {
+        if self.batch {
+            return Err(CondError::BatchNotWith(self.meta.entity.clone()));
+        }
+        let current = *index;
+        try!(self.detach_with_batch(current, &mut Vec::new(), batches));
+        for join in self.joins.iter_mut() {
+            *index += 1;
+            try!(join.select.detach_batch(index, batches));
+        }
+        Ok(())
+    }
+    fn detach_with_batch(&mut self,
+                         index: usize,
+                         path: &mut Vec<String>,
+                         batches: &mut Vec<BatchWith>)
+                         -> Result<(), CondError> {
+        let withs = mem::replace(&mut self.withs, Vec::new());
+        for (field, mut select) in withs.into_iter() {
+            if select.batch {
+                match self.meta.field_map.get(&field) {
+                    Some(&FieldMeta::OneToMany { .. }) => {}
+                    _ => {
+                        let entity = self.meta.entity.clone();
+                        return Err(CondError::NotOneToManyField(entity, field));
+                    }
+                }
+                batches.push((index, path.clone(), field, select));
+                continue;
+            }
+            path.push(field.clone());
+            try!(select.detach_with_batch(index, path, batches));
+            path.pop();
+            self.withs.push((field, select));
+        }
+        Ok(())
+    }
+    // path记录从根开始经过的Entity.field, 已经在path上的关系不再展开, 防止自引用死循环
+    fn inner_expand_eager(&mut self, path: &mut Vec<String>) {
+        let a_meta = self.meta;
+        for a_b_meta in a_meta.get_fields() {
+            if !a_b_meta.is_type_refer() || !a_b_meta.is_fetch_eager() {
+                continue;
+            }
+            let field = a_b_meta.get_field_name();
+            let key = format!("{}.{}", a_meta.entity, field);
+            if path.contains(&key) || self.withs.iter().any(|&(ref with, _)| with == &field) {
+                continue;
+            }
+            self.with(&field);
+        }
+        for &mut (ref field, ref mut select) in self.withs.iter_mut() {
+            path.push(format!("{}.{}", a_meta.entity, field));
+            select.inner_expand_eager(path);
+            path.pop();
+        }
+        for join in self.joins.iter_mut() {
+            join.select.inner_expand_eager(&mut Vec::new());
+        }
+    }
+    // "ptr.int_val"路径上的关系已经with过的直接复用, 否则加一个hidden的with
+    fn inner_expand_paths(&mut self) {
+        let paths = self.cond.as_ref().map_or(Vec::new(), |cond| cond.get_paths());
+        for path in paths.iter() {
+            self.ensure_path(path);
+        }
+        for &mut (_, ref mut select) in self.withs.iter_mut() {
+            select.inner_expand_paths();
+        }
+        for join in self.joins.iter_mut() {
+            join.select.inner_expand_paths();
+        }
+    }
+    // 第一段不是关系字段时(如引用其他别名的"t2.int_val")不处理, 由check负责报错
+    fn ensure_path(&mut self, path: &[String]) {
+        if path.len() == 0 {
+            return;
+        }
+        let field = &path[0];
+        match self.meta.field_map.get(field) {
+            Some(field_meta) if field_meta.is_type_refer() => {}
+            _ => return,
+        }
+        let pos = self.withs.iter().position(|&(ref with, _)| with == field);
+        let select = match pos {
+            Some(pos) => &mut self.withs[pos].1,
+            None => self.with_impl(field, true),
+        };
+        select.ensure_path(&path[1..]);
+    }
+    // 重新设置别名, with和自动生成别名的join跟着变化, 指定的别名保持不变
+    fn set_alias(&mut self, alias: String) {
+        for &mut (ref field, ref mut select) in self.withs.iter_mut() {
+            select.set_alias(format!("{}_{}", alias, field));
+        }
+        for join in self.joins.iter_mut() {
+            if join.select.named {
+                continue;
+            }
+            let b_alias = format!("{}_{}", alias, join.select.meta.alias);
+            join.select.set_alias(b_alias);
+        }
+        self.alias = alias;
+    }
+    // 保证整个语句中别名不重复, 自动生成的join别名冲突时加上数字后缀
+    // with的别名由路径决定, 条件中的路径依赖它, 所以with和指定的别名冲突时直接报错
+    fn unique_aliases(&mut self, used: &mut HashSet<String>) -> Result<(), CondError> {
+        if used.contains(&self.alias) {
+            return Err(CondError::DuplicateAlias(self.alias.clone()));
+        }
+        used.insert(self.alias.clone());
+        for &mut (_, ref mut select) in self.withs.iter_mut() {
+            try!(select.unique_aliases(used));
+        }
+        for join in self.joins.iter_mut() {
+            if !join.select.named && used.contains(&join.select.alias) {
+                let mut n = 2;
+                while used.contains(&format!("{}_{}", join.select.alias, n)) {
+                    n += 1;
+                }
+                let b_alias = format!("{}_{}", join.select.alias, n);
+                join.select.set_alias(b_alias);
+            }
+            try!(join.select.unique_aliases(used));
+        }
+        Ok(())
+    }
+    // 收集整个语句的别名, 每个select都保存一份, 检查和生成条件时用来解析"alias.field"
+    fn bind_scope(&mut self) {
+        let mut scope = HashMap::new();
+        self.get_scope(&mut scope);
+        self.set_scope(&scope);
+    }
+    fn get_scope(&self, scope: &mut Scope) {
+        scope.insert(self.alias.clone(), self.meta);
+        for &(_, ref select) in self.withs.iter() {
+            select.get_scope(scope);
+        }
+        for join in self.joins.iter() {
+            join.select.get_scope(scope);
+        }
+    }
+    fn set_scope(&mut self, scope: &Scope) {
+        self.scope = scope.clone();
+        for &mut (_, ref mut select) in self.withs.iter_mut() {
+            select.set_scope(scope);
+        }
+        for join in self.joins.iter_mut() {
+            join.select.set_scope(scope);
+        }
+    }
+    // exists/in子查询自成一个语句, 单独展开后检查
+    // 关联条件左边为外层的字段, 右边为子查询的字段, 都不能引用别名
+    pub fn check_subquery(&self, outer_meta: &EntityMeta) -> Result<(), CondError> {
+        let mut select = self.clone();
+        select.inner_expand_paths();
+        try!(select.unique_aliases(&mut HashSet::new()));
+        select.bind_scope();
+        try!(select.check_cond());
+        match select.correlation {
+            Some(ref cond) => {
+                cond.check("",
+                           outer_meta,
+                           &select.alias,
+                           select.meta,
+                           select.orm_meta,
+                           &HashMap::new())
+            }
+            None => Ok(()),
+        }
+    }
+    // where/on中的字段必须是当前entity的普通字段, 经过关系的路径, 或者是"alias.field"
+    // 需要在展开并绑定别名之后调用
+    fn check_cond(&self) -> Result<(), CondError> {
+        // filter只作用于with的ON, 根对象应该用wher
+        if self.filter.is_some() {
+            return Err(CondError::FilterNotWith(self.meta.entity.clone()));
+        }
+        self.inner_check_cond()
+    }
+    fn inner_check_cond(&self) -> Result<(), CondError> {
+        if let Some(ref cond) = self.cond {
+            try!(cond.check_in(self.meta, self.orm_meta, &self.scope));
+        }
+        for field in self.fields.iter().flat_map(|fields| fields.iter()) {
+            let entity = &self.meta.entity;
+            match self.meta.field_map.get(field) {
+                None => return Err(CondError::UnknownField(entity.clone(), field.clone())),
+                Some(field_meta) if field_meta.is_type_refer() => {
+                    return Err(CondError::ReferField(entity.clone(), field.clone()));
+                }
+                _ => {}
+            }
+        }
+        for group in self.groups.iter() {
+            try!(cond::resolve_expr(&self.alias, self.meta, self.orm_meta, &self.scope, group));
+        }
+        if let Some(ref cond) = self.having {
+            try!(cond.check_having(self.meta, self.orm_meta, &self.scope));
+        }
+        for &(ref field, _) in self.orders.iter() {
+            let entity = &self.meta.entity;
+            match self.meta.field_map.get(field) {
+                None => return Err(CondError::UnknownField(entity.clone(), field.clone())),
+                Some(field_meta) if field_meta.is_type_refer() => {
+                    return Err(CondError::ReferField(entity.clone(), field.clone()));
+                }
+                _ => {}
+            }
+        }
+        for &(_, ref select) in self.withs.iter() {
+            if let Some(ref cond) = select.filter {
+                let b_meta = select.meta;
+                try!(cond.check_in(b_meta, self.orm_meta, &self.scope));
+                if let Some(path) = get_refer_paths(b_meta, cond).first() {
+                    return Err(CondError::PathInJoinOn(b_meta.entity.clone(), path.join(".")));
+                }
+            }
+            try!(select.inner_check_cond());
+        }
+        for join in self.joins.iter() {
+            if let Some(ref err) = join.error {
+                return Err(err.clone());
+            }
+            try!(join.join_cond.check(&self.alias,
+                                      self.meta,
+                                      &join.select.alias,
+                                      join.select.meta,
+                                      self.orm_meta,
+                                      &self.scope));
+            if let Some(ref cond) = join.on_cond {
+                let b_meta = join.select.meta;
+                try!(cond.check_in(b_meta, self.orm_meta, &self.scope));
+                // 路径的JOIN在ON之后才出现, ON中不能引用
+                if let Some(path) = get_refer_paths(b_meta, cond).first() {
+                    return Err(CondError::PathInJoinOn(b_meta.entity.clone(), path.join(".")));
+                }
+            }
+            // join的对象应该用on
+            if join.select.filter.is_some() {
+                return Err(CondError::FilterNotWith(join.select.meta.entity.clone()));
+            }
+            try!(join.select.inner_check_cond());
+        }
+        Ok(())
+    }
     fn flat_select(&self) -> Vec<&SelectImpl> {
         let mut subs =
             self.joins.iter().flat_map(|join| join.select.flat_select()).collect::<Vec<_>>();
@@ -179,8 +869,135 @@ impl SelectImpl {
     pub fn query_inner_ex<C>(&self, conn: &mut C) -> Result<Vec<Vec<EntityInnerPointer>>, Error>
         where C: GenericConnection
     {
-        let sql = self.get_sql();
-        let params = self.get_params();
+        self.query_inner_map(conn, &mut HashMap::new())
+    }
+    // map为identity map，同一个(entity, id)只会对应一个对象
+    pub fn query_inner_map<C>(&self,
+                              conn: &mut C,
+                              map: &mut HashMap<String, EntityInnerPointer>)
+                              -> Result<Vec<Vec<EntityInnerPointer>>, Error>
+        where C: GenericConnection
+    {
+        let (select, batches) = try!(self.expand_batch(true));
+        let ret = try!(select.query_expanded(conn, map));
+        for &(index, ref path, ref field, ref b_select) in batches.iter() {
+            let a_rcs = collect_path(&ret[index], path);
+            try!(SelectImpl::query_batch(conn, map, &a_rcs, field, b_select));
+        }
+        Ok(ret)
+    }
+    // WHERE b.test_id IN (a.id, ...), 查出的对象按照外键放回对应的a中, 没有的为空的Vec
+    fn query_batch<C>(conn: &mut C,
+                      map: &mut HashMap<String, EntityInnerPointer>,
+                      a_rcs: &Vec<EntityInnerPointer>,
+                      field: &str,
+                      select: &SelectImpl)
+                      -> Result<(), Error>
+        where C: GenericConnection
+    {
+        if a_rcs.len() == 0 {
+            return Ok(());
+        }
+        let a_meta = a_rcs[0].borrow().meta;
+        let (a_field, b_field) = a_meta.field_map.get(field).unwrap().get_refer_lr();
+        let get_key = |rc: &EntityInnerPointer, field: &str| -> Option<u64> {
+            let inner = rc.borrow();
+            match inner.is_loaded(field) {
+                true => inner.get_value::<Option<u64>>(field),
+                false => None,
+            }
+        };
+        let keys = a_rcs.iter().filter_map(|rc| get_key(rc, &a_field)).collect::<Vec<_>>();
+        let mut b_rcs = Vec::new();
+        if keys.len() > 0 {
+            let mut select = select.clone();
+            // 单独查询时作为根, 不再是batch
+            select.batch = false;
+            let mut conds = vec![Cond::by_in_list(&b_field, keys)];
+            conds.extend(select.filter.take());
+            conds.extend(select.cond.take());
+            select.cond = Some(Cond::all_of(conds));
+            // 外键必须加载, 否则无法放回
+            if let Some(ref mut fields) = select.fields {
+                fields.push(b_field.clone());
+            }
+            b_rcs = try!(select.query_inner_map(conn, map)).remove(0);
+        }
+        let mut groups: HashMap<u64, Vec<EntityInnerPointer>> = HashMap::new();
+        for b_rc in b_rcs.into_iter() {
+            if let Some(key) = get_key(&b_rc, &b_field) {
+                groups.entry(key).or_insert_with(Vec::new).push(b_rc);
+            }
+        }
+        for a_rc in a_rcs.iter() {
+            let vec = get_key(a_rc, &a_field)
+                .and_then(|key| groups.get(&key).cloned())
+                .unwrap_or(Vec::new());
+            a_rc.borrow_mut().field_map.insert(field.to_string(), FieldValue::from(vec));
+        }
+        Ok(())
+    }
+    // 按行返回根对象和第index个join的对象, 相同的组合只返回一次
+    pub fn query_pairs<C>(&self,
+                          conn: &mut C,
+                          index: usize)
+                          -> Result<Vec<(Option<EntityInnerPointer>, Option<EntityInnerPointer>)>,
+                                    Error>
+        where C: GenericConnection
+    {
+        let (select, batches) = try!(self.expand_batch(true));
+        let (sql, params) = select.get_sql_params();
+        log!("{}", sql);
+        log!("\t{:?}", params);
+        let res = match params.len() {
+            0 => conn.prep_exec(sql, ()),
+            _ => conn.prep_exec(sql, params),
+        };
+        let query_result = try!(res);
+        let b_select = &select.joins[index].select;
+        let mut map = HashMap::new();
+        let mut set = HashSet::new();
+        let mut ret = Vec::new();
+        for row in query_result {
+            let mut row = try!(row);
+            let a_rc = select.pick_inner(&mut row, &mut map);
+            let b_rc = b_select.pick_inner(&mut row, &mut map);
+            if a_rc.is_none() && b_rc.is_none() {
+                continue;
+            }
+            let key = (a_rc.as_ref().map(|rc| rc.borrow().get_addr()),
+                       b_rc.as_ref().map(|rc| rc.borrow().get_addr()));
+            if !set.contains(&key) {
+                set.insert(key);
+                ret.push((a_rc, b_rc));
+            }
+        }
+        // 过滤one_many中的重复数据
+        let mut a_vec = ret.iter().filter_map(|&(ref a_rc, _)| a_rc.clone()).collect::<Vec<_>>();
+        let mut b_vec = ret.iter().filter_map(|&(_, ref b_rc)| b_rc.clone()).collect::<Vec<_>>();
+        dup_filter(&mut a_vec);
+        dup_filter(&mut b_vec);
+        // 只有根对象和第index个join的对象会返回, 其他join上的batch不需要查询
+        let b_index = select.joins[..index]
+            .iter()
+            .fold(1, |acc, join| acc + join.select.flat_select().len());
+        for &(i, ref path, ref field, ref c_select) in batches.iter() {
+            let rcs = match i {
+                0 => collect_path(&a_vec, path),
+                i if i == b_index => collect_path(&b_vec, path),
+                _ => continue,
+            };
+            try!(SelectImpl::query_batch(conn, &mut map, &rcs, field, c_select));
+        }
+        Ok(ret)
+    }
+    fn query_expanded<C>(&self,
+                         conn: &mut C,
+                         map: &mut HashMap<String, EntityInnerPointer>)
+                         -> Result<Vec<Vec<EntityInnerPointer>>, Error>
+        where C: GenericConnection
+    {
+        let (sql, params) = self.get_sql_params();
         log!("{}", sql);
         log!("\t{:?}", params);
         let res = match params.len() {
@@ -192,7 +1009,6 @@ impl SelectImpl {
         if res.is_err() {
             return Err(res.err().unwrap());
         }
-        let mut map = HashMap::new();
         let query_result = res.unwrap();
         let mut selects = self.flat_select();
         let ret = selects.iter().map(|_| Vec::new()).collect::<Vec<_>>();
@@ -208,7 +1024,7 @@ impl SelectImpl {
                 let mut row = item.as_mut().unwrap();
                 // 循环每个select读取
                 for (i, select) in selects.iter().enumerate() {
-                    let rc = select.pick_inner(&mut row, &mut map);
+                    let rc = select.pick_inner(&mut row, map);
                     rc.map(|rc| acc.get_mut(i).unwrap().push(rc));
                 }
                 return Ok(acc);
@@ -229,12 +1045,154 @@ impl SelectImpl {
         // })
         // Ok(Vec::new())
     }
+    // 根对象的数量, with/join造成的重复行只算一次
+    pub fn query_count<C>(&self, conn: &mut C) -> Result<u64, Error>
+        where C: GenericConnection
+    {
+        let expr = format!("COUNT(DISTINCT {}.id)", self.alias);
+        self.query_scalar(conn, &expr, &[])
+    }
+    // func为带一个{}的聚合表达式, 如"SUM({})", field可以是"ptr.int_val"这样的路径
+    pub fn query_aggregate<C, T>(&self,
+                                 conn: &mut C,
+                                 func: &str,
+                                 field: &str)
+                                 -> Result<T, Error>
+        where C: GenericConnection,
+              T: FromValue
+    {
+        let (alias, _, field_meta) =
+            try!(cond::resolve(&self.alias, self.meta, self.orm_meta, field));
+        let column = format!("{}.{}", alias, field_meta.get_column_name());
+        let expr = func.replace("{}", &column);
+        let mut path = field.split('.').map(String::from).collect::<Vec<_>>();
+        path.pop();
+        self.query_scalar(conn, &expr, &path)
+    }
+    // 投影查询, 不加载对象, 每行转换为R, 如(u64, i64)或者实现了FromRow的结构
+    // exprs可以是字段、路径或者聚合, 如["test_id", "count(*)", "sum(ptr.int_val)"]
+    pub fn project<C, R>(&self, conn: &mut C, exprs: &[&str]) -> Result<Vec<R>, Error>
+        where C: GenericConnection,
+              R: FromRow
+    {
+        // 先加上投影和分组需要的路径, 再统一处理别名, 新加的with也参与去重
+        // 不加载对象, batch的with直接去掉
+        let mut select = self.clone();
+        try!(select.detach_batch(&mut 0, &mut Vec::new()));
+        let mut paths = exprs.iter()
+            .map(|expr| cond::get_expr_path(expr))
+            .chain(self.groups.iter().map(|group| cond::get_expr_path(group)))
+            .collect::<Vec<_>>();
+        paths.extend(self.having.as_ref().map_or(Vec::new(), |cond| cond.get_paths()));
+        for path in paths.iter() {
+            select.ensure_path(path);
+        }
+        select.inner_expand_paths();
+        try!(select.unique_aliases(&mut HashSet::new()));
+        select.bind_scope();
+        try!(select.check_cond());
+        let mut columns = Vec::new();
+        for expr in exprs.iter() {
+            let column = try!(cond::resolve_expr(&select.alias,
+                                                 select.meta,
+                                                 select.orm_meta,
+                                                 &select.scope,
+                                                 expr));
+            columns.push(column);
+        }
+
+        let mut params = Vec::new();
+        let tables = select.get_tables(&mut params).join("\n\t");
+        let conds = select.get_conds(&mut params).join("\n\tAND ");
+        let mut sql = format!("SELECT\n\t{}\nFROM\n\t{}\nWHERE\n\t{}",
+                              columns.join(",\n\t"),
+                              tables,
+                              conds);
+        if select.groups.len() > 0 {
+            let groups = select.groups
+                .iter()
+                .map(|group| {
+                    cond::resolve_expr(&select.alias,
+                                       select.meta,
+                                       select.orm_meta,
+                                       &select.scope,
+                                       group)
+                })
+                .map(Result::unwrap)
+                .collect::<Vec<_>>();
+            sql.push_str(&format!("\nGROUP BY\n\t{}", groups.join(",\n\t")));
+        }
+        if let Some(ref cond) = select.having {
+            let having = cond.to_sql_in(&select.alias,
+                                        select.meta,
+                                        select.orm_meta,
+                                        &select.scope,
+                                        &mut params);
+            sql.push_str(&format!("\nHAVING\n\t{}", having));
+        }
+        let orders = self.get_self_orders();
+        if orders.len() > 0 {
+            sql.push_str(&format!("\nORDER BY\n\t{}", orders.join(",\n\t")));
+        }
+        if self.limit.is_some() || self.offset.is_some() {
+            sql.push_str(&format!("\n{}", self.get_limit_sql()));
+        }
+        log!("{}", sql);
+        log!("\t{:?}", params);
+        let res = match params.len() {
+            0 => conn.prep_exec(sql, ()),
+            _ => conn.prep_exec(sql, params),
+        };
+        let query_result = try!(res);
+        query_result.fold(Ok(Vec::new()), |acc, row| {
+            acc.and_then(|mut acc| {
+                let row = try!(row);
+                let item = try!(from_row_opt::<R>(row).map_err(|err| Error::FromRowError(err.0)));
+                acc.push(item);
+                Ok(acc)
+            })
+        })
+    }
+    // SELECT expr FROM ... WHERE root.id IN (SELECT root.id ...), 不加载对象
+    // where/with/join的条件放在子查询中只用来过滤根对象, one_many的with不会使行数相乘而多算
+    fn query_scalar<C, T>(&self, conn: &mut C, expr: &str, path: &[String]) -> Result<T, Error>
+        where C: GenericConnection,
+              T: FromValue
+    {
+        // batch的with只加载数据, 不参与过滤
+        let (filter, _) = try!(self.expand_batch(true));
+        // 外层只JOIN聚合字段路径上的关系
+        let mut select = SelectImpl::from_alias(self.meta, self.orm_meta, self.alias.clone());
+        select.ensure_path(path);
+        let alias = &self.alias;
+        let mut params = Vec::new();
+        let tables = select.get_tables(&mut params).join("\n\t");
+        let filter_tables = filter.get_tables(&mut params).join(" ");
+        let filter_conds = filter.get_conds(&mut params).join(" AND ");
+        let ids = format!("SELECT {}.id FROM {} WHERE {}", alias, filter_tables, filter_conds);
+        let sql = format!("SELECT\n\t{}\nFROM\n\t{}\nWHERE\n\t{}.id IN ({})",
+                          expr,
+                          tables,
+                          alias,
+                          ids);
+        log!("{}", sql);
+        log!("\t{:?}", params);
+        let res = match params.len() {
+            0 => conn.prep_exec(sql, ()),
+            _ => conn.prep_exec(sql, params),
+        };
+        let mut query_result = try!(res);
+        // 没有GROUP BY的聚合查询总是返回一行
+        let mut row = try!(query_result.next().expect(&expect!()));
+        let value = row.take::<Value, usize>(0).expect(&expect!());
+        from_value_opt::<T>(value).map_err(|err| Error::FromValueError(err.0))
+    }
     fn pick_self(&self,
                  row: &mut Row,
                  map: &mut HashMap<String, EntityInnerPointer>)
                  -> Option<EntityInnerPointer> {
         let a_rc = EntityInner::new_pointer(self.meta, self.orm_meta);
-        for field_meta in self.meta.get_non_refer_fields() {
+        for field_meta in self.get_select_fields() {
             let field = field_meta.get_field_name();
             let key = format!("{}${}", self.alias, field);
             row.get::<Value, &str>(&key).map(|value| {
@@ -246,10 +1204,21 @@ impl SelectImpl {
         if a_rc.borrow().get_id_u64().is_none() {
             return None;
         }
+        // 刚加载的对象没有修改过
+        a_rc.borrow_mut().reset_dirty();
         // 写入map防止重复对象
-        let key = format!("{}_{}", self.alias, a_rc.borrow().get_id_u64().unwrap());
-        map.entry(key.clone()).or_insert(a_rc.clone());
-        let a_rc = map.get(&key).unwrap().clone();
+        let key = identity_key(self.meta, a_rc.borrow().get_id_u64().unwrap());
+        if let Some(rc) = map.get(&key) {
+            // 已经存在时只补上之前没有加载的字段(如之前用columns只查了部分字段), 已有的值保持不变
+            let mut inner = rc.borrow_mut();
+            for (field, value) in a_rc.borrow().field_map.iter() {
+                if !inner.field_map.contains_key(field) {
+                    inner.field_map.insert(field.clone(), value.clone());
+                }
+            }
+            return Some(rc.clone());
+        }
+        map.insert(key, a_rc.clone());
         Some(a_rc)
     }
     fn pick_inner(&self,
@@ -264,7 +1233,7 @@ impl SelectImpl {
         }
         let a_rc = a_rc.unwrap();
 
-        for &(ref a_b_field, ref select) in self.withs.iter() {
+        for &(ref a_b_field, ref select) in self.withs.iter().filter(|&&(_, ref s)| !s.hidden) {
             let field_meta = a_meta.field_map.get(a_b_field).unwrap();
             let b_rc = select.pick_inner(row, map);
             match field_meta {
@@ -299,34 +1268,146 @@ impl SelectImpl {
     }
     // select [A.a as A_a, B.b as B_b] from [A_t as A] join [B_t as B on A.a_id = B.id] where A.id > 10
     pub fn get_sql(&self) -> String {
+        self.get_sql_params().0
+    }
+    pub fn get_params(&self) -> Vec<(String, Value)> {
+        self.get_sql_params().1
+    }
+    // sql和参数在同一次遍历中生成, 参数的顺序与占位符在sql中出现的顺序一致
+    pub fn get_sql_params(&self) -> (String, Vec<(String, Value)>) {
+        let mut params = Vec::new();
         let columns = self.get_columns()
             .into_iter()
             .map(|vec| vec.join(",\n\t"))
             .collect::<Vec<_>>()
             .join(",\n\n\t");
-        let tables = self.get_tables().join("\n\t");
-        let conds = self.get_conds().join("\n\tAND ");
-        format!("SELECT\n\t{}\nFROM\n\t{}\nWHERE\n\t{}",
+        let tables = self.get_tables(&mut params).join("\n\t");
+        let mut conds = self.get_conds(&mut params);
+        // 有with/join时一行数据不对应一个根对象, 不能直接LIMIT
+        let limited = self.limit.is_some() || self.offset.is_some();
+        let multi = self.withs.len() > 0 || self.joins.len() > 0;
+        if limited && multi {
+            let cond = self.get_limit_cond(&mut params);
+            conds.push(cond);
+        }
+        let mut sql = format!("SELECT\n\t{}\nFROM\n\t{}\nWHERE\n\t{}",
+                              columns,
+                              tables,
+                              conds.join("\n\tAND "));
+        let orders = self.get_orders();
+        if orders.len() > 0 {
+            sql.push_str(&format!("\nORDER BY\n\t{}", orders.join(",\n\t")));
+        }
+        if limited && !multi {
+            sql.push_str(&format!("\n{}", self.get_limit_sql()));
+        }
+        (sql, params)
+    }
+    pub fn get_orders(&self) -> Vec<String> {
+        let mut ret = self.get_self_orders();
+        for &(_, ref select) in self.withs.iter().filter(|&&(_, ref s)| !s.hidden) {
+            ret.append(&mut select.get_orders());
+        }
+        for join in self.joins.iter() {
+            ret.append(&mut join.select.get_orders());
+        }
+        ret
+    }
+    fn get_self_orders(&self) -> Vec<String> {
+        self.orders
+            .iter()
+            .map(|&(ref field, ref order)| {
+                let column = self.meta.field_map.get(field).unwrap().get_column_name();
+                format!("{}.{} {}", self.alias, column, order.to_sql())
+            })
+            .collect()
+    }
+    fn get_limit_sql(&self) -> String {
+        // MySQL的OFFSET必须跟在LIMIT后面
+        let limit = self.limit.unwrap_or(u64::max_value());
+        match self.offset {
+            Some(offset) => format!("LIMIT {} OFFSET {}", limit, offset),
+            None => format!("LIMIT {}", limit),
+        }
+    }
+    // alias.id IN (SELECT c0 FROM (SELECT DISTINCT alias.id AS c0 ... LIMIT n) AS alias_limit)
+    // 先按照根对象的排序取出一页的id, 再用id过滤
+    fn get_limit_cond(&self, params: &mut Vec<(String, Value)>) -> String {
+        let alias = &self.alias;
+        let mut columns = vec![format!("{}.id", alias)];
+        for &(ref field, _) in self.orders.iter() {
+            let column = self.meta.field_map.get(field).unwrap().get_column_name();
+            columns.push(format!("{}.{}", alias, column));
+        }
+        let columns = columns.iter()
+            .enumerate()
+            .map(|(i, column)| format!("{} AS c{}", column, i))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let tables = self.get_tables(params).join(" ");
+        let conds = self.get_conds(params).join(" AND ");
+        let orders = self.get_self_orders();
+        let orders = match orders.len() {
+            0 => "".to_string(),
+            _ => format!(" ORDER BY {}", orders.join(", ")),
+        };
+        format!("{}.id IN (SELECT c0 FROM (SELECT DISTINCT {} FROM {} WHERE {}{} {}) AS {}_limit)",
+                alias,
                 columns,
                 tables,
-                conds)
+                conds,
+                orders,
+                self.get_limit_sql(),
+                alias)
     }
-    pub fn get_params(&self) -> Vec<(String, Value)> {
-        self.inner_get_params()
+    // 嵌入到外层条件中的子查询, 别名加上外层别名作为前缀, 避免遮住外层的表
+    // select_id为false时用于EXISTS, 为true时用于IN
+    pub fn get_subquery_sql(&self,
+                            outer: &str,
+                            outer_meta: &EntityMeta,
+                            select_id: bool,
+                            params: &mut Vec<(String, Value)>)
+                            -> String {
+        let mut select = self.clone();
+        select.set_alias(format!("{}_{}", outer, self.alias));
+        select.inner_expand_paths();
+        // 检查时已经报过错, 这里的别名冲突不会再出现
+        let _ = select.unique_aliases(&mut HashSet::new());
+        select.bind_scope();
+        let alias = &select.alias;
+        let column = if select_id {
+            format!("{}.id", alias)
+        } else {
+            "1".to_string()
+        };
+        let tables = select.get_tables(params).join(" ");
+        let mut conds = select.inner_get_conds(params);
+        if let Some(ref join_cond) = select.correlation {
+            conds.push(join_cond.to_sql(outer,
+                                        outer_meta,
+                                        alias,
+                                        select.meta,
+                                        select.orm_meta,
+                                        &HashMap::new()));
+        }
+        if conds.len() == 0 {
+            conds.push("1 = 1".to_string());
+        }
+        format!("SELECT {} FROM {} WHERE {}", column, tables, conds.join(" AND "))
     }
-    pub fn get_conds(&self) -> Vec<String> {
-        let mut vec = self.inner_get_conds();
+    pub fn get_conds(&self, params: &mut Vec<(String, Value)>) -> Vec<String> {
+        let mut vec = self.inner_get_conds(params);
         if vec.len() == 0 {
             vec.push("1 = 1".to_string());
         }
         vec
     }
-    pub fn get_tables(&self) -> Vec<String> {
+    pub fn get_tables(&self, params: &mut Vec<(String, Value)>) -> Vec<String> {
         let a_meta = self.meta;
         let a_entity = &a_meta.entity;
         let a_table = &a_meta.table;
         let self_table = format!("{} as {}", a_table, &self.alias);
-        let mut tables = self.inner_get_tables();
+        let mut tables = self.inner_get_tables(params);
         tables.insert(0, self_table);
         tables
     }
@@ -334,47 +1415,44 @@ impl SelectImpl {
         self.inner_get_columns()
     }
 
-    fn inner_get_params(&self) -> Vec<(String, Value)> {
+    fn inner_get_conds(&self, params: &mut Vec<(String, Value)>) -> Vec<String> {
         let alias = &self.alias;
-        let mut with_params = self.withs
-            .iter()
-            .flat_map(|&(_, ref select)| select.inner_get_params())
-            .collect::<Vec<_>>();
-        let mut join_params = self.joins
-            .iter()
-            .flat_map(|join| {
-                join.on_cond.as_ref().map_or(Vec::new(), |cond| cond.to_params(&join.select.alias))
-            })
-            .collect::<Vec<_>>();
-        let mut join_select_params = self.joins
-            .iter()
-            .flat_map(|join| join.select.inner_get_params())
-            .collect::<Vec<_>>();
-        let mut ret = self.cond.as_ref().map_or(Vec::new(), |cond| cond.to_params(alias));
-        ret.append(&mut with_params);
-        ret.append(&mut join_params);
-        ret.append(&mut join_select_params);
+        let mut ret = self.cond.as_ref().map_or(Vec::new(), |cond| {
+            vec![cond.to_sql_in(alias, self.meta, self.orm_meta, &self.scope, params)]
+        });
+        for &(_, ref select) in self.withs.iter() {
+            ret.append(&mut select.inner_get_conds(params));
+        }
+        for join in self.joins.iter() {
+            ret.append(&mut join.select.inner_get_conds(params));
+        }
         ret
     }
-    fn inner_get_conds(&self) -> Vec<String> {
-        let alias = &self.alias;
-        let mut with_conds = self.withs
-            .iter()
-            .flat_map(|&(_, ref select)| select.inner_get_conds())
-            .collect::<Vec<_>>();
-        let mut join_select_cond = self.joins
-            .iter()
-            .flat_map(|join| join.select.inner_get_conds())
-            .collect::<Vec<_>>();
-        let mut ret = self.cond.as_ref().map_or(Vec::new(), |cond| vec![cond.to_sql(alias)]);
-        ret.append(&mut with_conds);
-        ret.append(&mut join_select_cond);
-        ret
+    // 没有指定columns时为所有非refer字段
+    fn get_select_fields(&self) -> Vec<&FieldMeta> {
+        let fields = match self.fields {
+            Some(ref fields) => fields,
+            None => return self.meta.get_non_refer_fields(),
+        };
+        // 关系的外键也总是加载, 否则之后无法懒加载关系字段
+        let lefts = self.meta
+            .get_fields()
+            .into_iter()
+            .filter(|field_meta| field_meta.is_type_refer())
+            .map(|field_meta| field_meta.get_refer_lr().0)
+            .collect::<HashSet<_>>();
+        self.meta
+            .get_non_refer_fields()
+            .into_iter()
+            .filter(|field_meta| {
+                let field = field_meta.get_field_name();
+                field_meta.is_type_id() || fields.contains(&field) || lefts.contains(&field)
+            })
+            .collect()
     }
     fn inner_get_columns(&self) -> Vec<Vec<String>> {
         let alias = &self.alias;
-        let self_columns = self.meta
-            .get_non_refer_fields()
+        let self_columns = self.get_select_fields()
             .into_iter()
             .map(|field_meta| {
                 let column = field_meta.get_column_name();
@@ -384,6 +1462,7 @@ impl SelectImpl {
             .collect::<Vec<_>>();
         let mut with_columns = self.withs
             .iter()
+            .filter(|&&(_, ref select)| !select.hidden)
             .flat_map(|&(_, ref select)| select.inner_get_columns())
             .collect::<Vec<_>>();
         let mut join_columns =
@@ -393,58 +1472,89 @@ impl SelectImpl {
         ret.append(&mut join_columns);
         ret
     }
-    fn inner_get_tables(&self) -> Vec<String> {
+    fn inner_get_tables(&self, params: &mut Vec<(String, Value)>) -> Vec<String> {
         let alias = &self.alias;
         let a_meta = self.meta;
-        let mut with_tables = self.withs
-            .iter()
-            .flat_map(|&(ref a_b_field, ref select)| {
-                let a_b_meta = a_meta.field_map.get(a_b_field).unwrap();
-                let b_alias = format!("{}_{}", alias, a_b_field);
-                let b_meta = select.meta;
-                let b_table = &b_meta.table;
-                let mut vec = select.inner_get_tables();
-                let (a_field, b_field) = a_b_meta.get_refer_lr();
-                let a_column = a_meta.field_map.get(&a_field).unwrap().get_column_name();
-                let b_column = b_meta.field_map.get(&b_field).unwrap().get_column_name();
-                let join_table = format!("LEFT JOIN {} AS {} ON {}.{} = {}.{}",
-                                         b_table,
-                                         b_alias,
-                                         alias,
-                                         a_column,
-                                         b_alias,
-                                         b_column);
-                vec.insert(0, join_table);
-                vec
-            })
-            .collect::<Vec<_>>();
-        let mut join_tables = self.joins
-            .iter()
-            .flat_map(|join| {
-                let b_meta = join.select.meta;
-                let b_entity = &b_meta.entity;
-                let b_table = &b_meta.table;
-                let b_alias = &join.select.alias;
-                let join_cond = join.join_cond.to_sql(alias, &b_alias);
-                let on_cond = join.on_cond.as_ref().map_or("".to_string(), |cond| {
-                    format!(" AND {}", cond.to_sql(&b_alias))
-                });
-                let cond = vec![join_cond, on_cond].join("");
-                let join_kind = join.kind.to_sql();
-                let join_table = format!("{} {} AS {} ON {}", join_kind, b_table, b_alias, cond);
-                let mut ret = vec![join_table];
-                let mut subs = join.select.inner_get_tables();
-                ret.append(&mut subs);
-                ret
-            })
-            .collect::<Vec<_>>();
         let mut ret: Vec<String> = Vec::new();
-        ret.append(&mut with_tables);
-        ret.append(&mut join_tables);
+        for &(ref a_b_field, ref select) in self.withs.iter() {
+            let a_b_meta = a_meta.field_map.get(a_b_field).unwrap();
+            let b_alias = format!("{}_{}", alias, a_b_field);
+            let b_meta = select.meta;
+            let b_table = &b_meta.table;
+            let (a_field, b_field) = a_b_meta.get_refer_lr();
+            let a_column = a_meta.field_map.get(&a_field).unwrap().get_column_name();
+            let b_column = b_meta.field_map.get(&b_field).unwrap().get_column_name();
+            let filter = select.filter.as_ref().map_or("".to_string(), |cond| {
+                format!(" AND {}",
+                        cond.to_sql_in(&b_alias, b_meta, self.orm_meta, &self.scope, params))
+            });
+            let join_table = format!("LEFT JOIN {} AS {} ON {}.{} = {}.{}{}",
+                                     b_table,
+                                     b_alias,
+                                     alias,
+                                     a_column,
+                                     b_alias,
+                                     b_column,
+                                     filter);
+            ret.push(join_table);
+            ret.append(&mut select.inner_get_tables(params));
+        }
+        for join in self.joins.iter() {
+            let b_meta = join.select.meta;
+            let b_table = &b_meta.table;
+            let b_alias = &join.select.alias;
+            let join_cond = join.join_cond
+                .to_sql(alias, self.meta, b_alias, b_meta, self.orm_meta, &self.scope);
+            let on_cond = join.on_cond.as_ref().map_or("".to_string(), |cond| {
+                format!(" AND {}",
+                        cond.to_sql_in(&b_alias, b_meta, self.orm_meta, &self.scope, params))
+            });
+            let cond = vec![join_cond, on_cond].join("");
+            let join_kind = join.kind.to_sql();
+            let join_table = format!("{} {} AS {} ON {}", join_kind, b_table, b_alias, cond);
+            ret.push(join_table);
+            ret.append(&mut join.select.inner_get_tables(params));
+        }
         ret
     }
 }
 
+pub fn identity_key(meta: &EntityMeta, id: u64) -> String {
+    format!("{}_{}", meta.entity, id)
+}
+
+// 条件中经过meta的关系字段的路径, "t2.int_val"这样引用其他别名的不算
+fn get_refer_paths(meta: &EntityMeta, cond: &Cond) -> Vec<Vec<String>> {
+    cond.get_paths()
+        .into_iter()
+        .filter(|path| meta.field_map.get(&path[0]).map_or(false, |field| field.is_type_refer()))
+        .collect()
+}
+
+// 沿着字段路径取出已经加载的对象, 同一个对象只出现一次
+fn collect_path(rcs: &Vec<EntityInnerPointer>, path: &[String]) -> Vec<EntityInnerPointer> {
+    if path.len() == 0 {
+        return rcs.clone();
+    }
+    let mut set = HashSet::new();
+    let mut ret = Vec::new();
+    for rc in rcs.iter() {
+        let b_rcs = match rc.borrow().field_map.get(&path[0]) {
+            Some(&FieldValue::Entity(Some(ref b_rc))) => vec![b_rc.clone()],
+            Some(&FieldValue::Vec(ref vec)) => vec.clone(),
+            _ => Vec::new(),
+        };
+        for b_rc in b_rcs.into_iter() {
+            let addr = b_rc.borrow().get_addr();
+            if !set.contains(&addr) {
+                set.insert(addr);
+                ret.push(b_rc);
+            }
+        }
+    }
+    collect_path(&ret, &path[1..])
+}
+
 fn dup_filter(vec: &mut Vec<EntityInnerPointer>) {
     let copy = vec.clone();
     vec.clear();
@@ -475,12 +1585,62 @@ fn dup_filter(vec: &mut Vec<EntityInnerPointer>) {
 }
 
 
-#[derive(Debug)]
+// map只保存当前根对象下的对象, 不同根对象之间共享的pointer不再是同一个对象
+pub struct SelectIter<'a, E> {
+    phantom: PhantomData<E>,
+    select: SelectImpl,
+    result: QueryResult<'a>,
+    map: HashMap<String, EntityInnerPointer>,
+    current: Option<EntityInnerPointer>,
+}
+
+impl<'a, E> SelectIter<'a, E>
+    where E: Entity
+{
+    fn finish(rc: EntityInnerPointer) -> E {
+        let mut vec = vec![rc];
+        dup_filter(&mut vec);
+        E::from_inner(vec.remove(0))
+    }
+}
+
+impl<'a, E> Iterator for SelectIter<'a, E>
+    where E: Entity
+{
+    type Item = Result<E, Error>;
+    fn next(&mut self) -> Option<Result<E, Error>> {
+        loop {
+            let mut row = match self.result.next() {
+                None => return self.current.take().map(|rc| Ok(Self::finish(rc))),
+                Some(Err(err)) => return Some(Err(err)),
+                Some(Ok(row)) => row,
+            };
+            let key = format!("{}$id", self.select.alias);
+            let id = row.get::<Value, &str>(&key).map_or(None, from_value::<Option<u64>>);
+            let current_id = self.current.as_ref().and_then(|rc| rc.borrow().get_id_u64());
+            if id.is_some() && current_id.is_some() && id != current_id {
+                // 换了一个根对象, 返回前一个
+                let done = self.current.take().map(|rc| Ok(Self::finish(rc)));
+                self.map.clear();
+                self.current = self.select.pick_inner(&mut row, &mut self.map);
+                return done;
+            }
+            let rc = self.select.pick_inner(&mut row, &mut self.map);
+            if self.current.is_none() {
+                self.current = rc;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Join {
     kind: JoinKind,
     join_cond: JoinCond,
     on_cond: Option<Cond>,
     select: SelectImpl,
+    // join_rel的字段不正确时记录在这里
+    error: Option<CondError>,
 }
 
 impl Join {
@@ -490,6 +1650,7 @@ impl Join {
             join_cond: join_cond,
             on_cond: None,
             select: select,
+            error: None,
         }
     }
     pub fn on(&mut self, cond: &Cond) -> &mut Self {
@@ -501,6 +1662,9 @@ impl Join {
     pub fn wher(&mut self, cond: &Cond) -> &mut SelectImpl {
         self.select.wher(cond)
     }
+    pub fn order_by(&mut self, field: &str, order: Order) -> &mut SelectImpl {
+        self.select.order_by(field, order)
+    }
     pub fn with(&mut self, field: &str) -> &mut SelectImpl {
         self.select.with(field)
     }
@@ -524,9 +1688,33 @@ impl Join {
     {
         self.select.outer_join::<Et>(join_cond)
     }
+    pub fn join_as<Et>(&mut self, alias: &str, join_cond: &JoinCond) -> &mut Join
+        where Et: Entity
+    {
+        self.select.join_as::<Et>(alias, join_cond)
+    }
+    pub fn left_join_as<Et>(&mut self, alias: &str, join_cond: &JoinCond) -> &mut Join
+        where Et: Entity
+    {
+        self.select.left_join_as::<Et>(alias, join_cond)
+    }
+    pub fn right_join_as<Et>(&mut self, alias: &str, join_cond: &JoinCond) -> &mut Join
+        where Et: Entity
+    {
+        self.select.right_join_as::<Et>(alias, join_cond)
+    }
+    pub fn join_rel(&mut self, field: &str) -> &mut Join {
+        self.select.join_rel(field)
+    }
+    pub fn left_join_rel(&mut self, field: &str) -> &mut Join {
+        self.select.left_join_rel(field)
+    }
+    pub fn right_join_rel(&mut self, field: &str) -> &mut Join {
+        self.select.right_join_rel(field)
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum JoinKind {
     Inner,
     Outer,
@@ -544,3 +1732,18 @@ impl JoinKind {
         }
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+impl Order {
+    pub fn to_sql(&self) -> String {
+        match self {
+            &Order::Asc => "ASC".to_string(),
+            &Order::Desc => "DESC".to_string(),
+        }
+    }
+}
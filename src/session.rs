@@ -1,747 +1,445 @@
-// #[macro_use]
-// use macros;
+#[macro_use]
+use macros;
 
-// use mysql::Error;
-// use mysql::Row;
-// use mysql::conn::GenericConnection;
+use mysql::Error;
+use mysql::Value;
+use mysql::PooledConn;
+use mysql::conn::GenericConnection;
 
-// use itertools::Itertools;
+use std::io;
+use std::rc::Rc;
+use std::rc::Weak;
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ops::DerefMut;
+use std::mem;
 
-// use std::rc::Rc;
-// use std::cell::Cell;
-// use std::cell::RefCell;
-// use std::collections::HashMap;
-// use std::ops::DerefMut;
+use cond::Cond;
+use entity::Entity;
+use entity::EntityInnerPointer;
+use select::Select;
+use select::SelectImpl;
+use select::identity_key;
+use execute::Execute;
+use execute::ExecuteSet;
+use value::FieldValue;
 
-// use cond::Cond;
-// use entity::Entity;
-// use entity::EntityInner;
-// use entity::EntityInnerPointer;
-// use select::Select;
+use meta::Cascade;
 
-// use meta::OrmMeta;
-// use meta::EntityMeta;
-// use meta::Cascade;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionStatus {
+    Normal,
+    Select,
+    Insert,
+    Update,
+    Delete,
+    Closed,
+}
 
-// #[derive(Clone, Copy, PartialEq)]
-// pub enum SessionStatus {
-//     Normal,
-//     Closed,
-// }
+// conn: 整个session共用一个连接
+// cache: 等待flush的对象，操作类型记录在对象的cascade上
+// identity: (entity, id) -> 对象，同一行数据只会有一个对象
+#[derive(Clone)]
+pub struct Session {
+    conn: Rc<RefCell<PooledConn>>,
+    cache: Rc<RefCell<Vec<EntityInnerPointer>>>,
+    identity: Rc<RefCell<HashMap<String, EntityInnerPointer>>>,
+    status: Rc<Cell<SessionStatus>>,
+}
 
-// // impl From<Cascade> for SessionStatus {
-// //     fn from(c: Cascade) -> SessionStatus {
-// //         match c {
-// //             Cascade::NULL => SessionStatus::Normal,
-// //             Cascade::Insert => SessionStatus::Insert,
-// //             Cascade::Update => SessionStatus::Update,
-// //             Cascade::Delete => SessionStatus::Delete,
-// //         }
-// //     }
-// // }
+// 对象上保存session的弱引用, 避免对象和session之间的循环引用
+// session被释放之后对象为游离态, 连接可以回到连接池
+#[derive(Clone)]
+pub struct WeakSession {
+    conn: Weak<RefCell<PooledConn>>,
+    cache: Weak<RefCell<Vec<EntityInnerPointer>>>,
+    identity: Weak<RefCell<HashMap<String, EntityInnerPointer>>>,
+    status: Weak<Cell<SessionStatus>>,
+}
 
-// pub struct Session {
-//     conn: Rc<RefCell<PooledConn>>,
-//     cache: Rc<RefCell<Vec<EntityInnerPointer>>>,
-//     status: Rc<Cell<SessionStatus>>,
-// }
-// impl Session {
-//     pub fn new(conn: PooledConn) -> Session {
-//         Session {
-//             conn: Rc::new(RefCell::new(conn)),
-//             cache: Rc::new(RefCell::new(Vec::new())),
-//             status: Rc::new(Cell::new(SessionStatus::Normal)),
-//         }
-//     }
-//     pub fn insert<E>(&self, entity: &E) -> Result<(), Error>
-//         where E: Entity
-//     {
-//         self.execute_inner(entity.inner(), Cascade::Insert)
-//     }
-//     pub fn update<E>(&self, entity: &E) -> Result<(), Error>
-//         where E: Entity
-//     {
-//         self.execute_inner(entity.inner(), Cascade::Update)
-//     }
-//     pub fn delete<E>(&self, entity: &E) -> Result<(), Error>
-//         where E: Entity
-//     {
-//         self.execute_inner(entity.inner(), Cascade::Delete)
-//     }
-//     pub fn select<E>(&self, cond: &Cond) -> Result<Vec<E>, Error>
-//         where E: Entity
-//     {
-//         self.select_inner(E::meta(), E::orm_meta(), cond).map(|vec| {
-//             vec.into_iter()
-//                 .map(E::from_inner)
-//                 .collect::<Vec<E>>()
-//         })
-//     }
-//     pub fn query<E>(&self, select: &Select) -> Result<Vec<E>, Error>
-//         where E: Entity
-//     {
-//         self.query_inner(select).map(|vec| vec.into_iter().map(E::from_inner).collect())
-//     }
-//     pub fn get<E>(&self, id: u64) -> Result<Option<E>, Error>
-//         where E: Entity
-//     {
-//         self.get_inner(E::meta(), E::orm_meta(), &Cond::by_id(id))
-//             .map(|opt| opt.map(E::from_inner))
-//     }
+impl WeakSession {
+    pub fn upgrade(&self) -> Option<Session> {
+        match (self.conn.upgrade(),
+               self.cache.upgrade(),
+               self.identity.upgrade(),
+               self.status.upgrade()) {
+            (Some(conn), Some(cache), Some(identity), Some(status)) => {
+                Some(Session {
+                    conn: conn,
+                    cache: cache,
+                    identity: identity,
+                    status: status,
+                })
+            }
+            _ => None,
+        }
+    }
+}
 
-//     pub fn close(&self) -> Result<(), Error> {
-//         let res = self.flush_cache();
-//         self.status.set(SessionStatus::Closed);
-//         res
-//     }
-//     pub fn status(&self) -> SessionStatus {
-//         self.status.get()
-//     }
-// }
+impl Session {
+    pub fn new(conn: PooledConn) -> Session {
+        Session {
+            conn: Rc::new(RefCell::new(conn)),
+            cache: Rc::new(RefCell::new(Vec::new())),
+            identity: Rc::new(RefCell::new(HashMap::new())),
+            status: Rc::new(Cell::new(SessionStatus::Normal)),
+        }
+    }
+    pub fn insert<E>(&self, entity: &E) -> Result<(), Error>
+        where E: Entity
+    {
+        self.push_cache(entity.inner(), Cascade::Insert)
+    }
+    pub fn update<E>(&self, entity: &E) -> Result<(), Error>
+        where E: Entity
+    {
+        self.push_cache(entity.inner(), Cascade::Update)
+    }
+    pub fn delete<E>(&self, entity: &E) -> Result<(), Error>
+        where E: Entity
+    {
+        self.push_cache(entity.inner(), Cascade::Delete)
+    }
+    pub fn query<E>(&self, select: &Select<E>) -> Result<Vec<E>, Error>
+        where E: Entity
+    {
+        self.query_inner(select.get_impl()).map(|vec| vec.into_iter().map(E::from_inner).collect())
+    }
+    pub fn get<E>(&self, id: u64) -> Result<Option<E>, Error>
+        where E: Entity
+    {
+        try!(self.ensure_not_closed());
+        let key = identity_key(E::meta(), id);
+        let cached = self.identity.borrow().get(&key).cloned();
+        if cached.is_some() {
+            return Ok(cached.map(E::from_inner));
+        }
+        let mut select = Select::<E>::new();
+        select.wher(&Cond::by_id(id));
+        self.query(&select).map(|mut vec| match vec.len() {
+            0 => None,
+            _ => Some(vec.swap_remove(0)),
+        })
+    }
+    pub fn flush(&self) -> Result<u64, Error> {
+        try!(self.ensure_not_closed());
+        // 执行过程中会重置脏字段、写入插入得到的id, 事务没有提交时要恢复, 之后可以重新flush
+        let snapshot = self.snapshot();
+        let res = {
+            let mut conn = self.conn.borrow_mut();
+            let mut trans = try!(conn.start_transaction(false, None, None));
+            match self.flush_cache(&mut trans) {
+                Ok(res) => trans.commit().map(|_| res),
+                Err(err) => trans.rollback().and_then(|_| Err(err)),
+            }
+        };
+        match res {
+            Ok(res) => {
+                self.finish_flush();
+                Ok(res)
+            }
+            Err(err) => {
+                restore(snapshot);
+                Err(err)
+            }
+        }
+    }
+    // flush失败时返回错误, session保持打开, 缓存的操作不会丢失
+    pub fn close(&self) -> Result<u64, Error> {
+        let res = try!(self.flush());
+        // 之后的对象都是游离态
+        for rc in self.identity.borrow().values().chain(self.cache.borrow().iter()) {
+            rc.borrow_mut().clear_session();
+        }
+        self.cache.borrow_mut().clear();
+        self.identity.borrow_mut().clear();
+        self.status.set(SessionStatus::Closed);
+        Ok(res)
+    }
+    pub fn status(&self) -> SessionStatus {
+        self.status.get()
+    }
+    pub fn downgrade(&self) -> WeakSession {
+        WeakSession {
+            conn: Rc::downgrade(&self.conn),
+            cache: Rc::downgrade(&self.cache),
+            identity: Rc::downgrade(&self.identity),
+            status: Rc::downgrade(&self.status),
+        }
+    }
+}
 
-// impl Session {
-//     pub fn push_cache(&self, rc: EntityInnerPointer) {
-//         self.cache.borrow_mut().push(rc);
-//     }
-//     fn flush_cache(&self) -> Result<(), Error> {
-//         // 一定要调用非guard函数！！，因为guard会调用flush_cache导致死循环
-//         let result = self.cache.borrow().iter().fold(Ok(()), |result, rc| {
-//             if result.is_err() {
-//                 return result;
-//             }
-//             // 如果对象上没有级联标记，默认进行UPDATE
-//             let op = rc.borrow().cascade.map_or(Cascade::Update, |op| op);
-//             self.execute_inner(rc.clone(), op)
-//         });
-//         // 重置动态级联标记
-//         for rc in self.cache.borrow().iter() {
-//             rc.borrow_mut().cascade_reset();
-//         }
-//         self.cache.borrow_mut().clear();
-//         result
-//     }
-// }
+struct StatusGuard {
+    status: Rc<Cell<SessionStatus>>,
+    old: SessionStatus,
+}
 
-// impl Session {
-//     fn batch_inner(&self, vec: &Vec<EntityInnerPointer>, op: Cascade) -> Result<(), Error> {
-//         self.guard(op.clone(), || {
-//             let result = self.batch_impl(vec, op);
-//             // 重置动态级联标记
-//             for rc in vec.iter() {
-//                 rc.borrow_mut().cascade_reset();
-//             }
-//             result
-//         })
-//     }
-//     fn execute_inner(&self, a_rc: EntityInnerPointer, op: Cascade) -> Result<(), Error> {
-//         let result = self.guard(op.clone(), || self.execute_impl(a_rc.clone(), op));
-//         a_rc.borrow_mut().cascade_reset();
-//         result
-//     }
-//     pub fn query_inner(&self, select: &Select) -> Result<Vec<EntityInnerPointer>, Error> {
-//         select.query_inner(self.conn.borrow_mut().deref_mut()).map(|vec| {
-//             for rc in vec.iter() {
-//                 rc.borrow_mut().set_session_recur(self.clone());
-//             }
-//             vec
-//         })
-//     }
-//     pub fn select_inner(&self,
-//                         meta: &'static EntityMeta,
-//                         orm_meta: &'static OrmMeta,
-//                         cond: &Cond)
-//                         -> Result<Vec<EntityInnerPointer>, Error> {
-//         self.guard(SessionStatus::Select,
-//                    || self.select_impl(meta, orm_meta, cond))
-//     }
-//     pub fn get_inner(&self,
-//                      meta: &'static EntityMeta,
-//                      orm_meta: &'static OrmMeta,
-//                      cond: &Cond)
-//                      -> Result<Option<EntityInnerPointer>, Error> {
-//         self.guard(SessionStatus::Select, || {
-//             self.select_impl(meta, orm_meta, cond).map(|mut vec| match vec.len() {
-//                 0 => None,
-//                 _ => Some(vec.swap_remove(0)),
-//             })
-//         })
-//     }
-//     pub fn clone(&self) -> Session {
-//         Session {
-//             conn: self.conn.clone(),
-//             cache: self.cache.clone(),
-//             status: self.status.clone(),
-//         }
-//     }
-// }
+impl Drop for StatusGuard {
+    fn drop(&mut self) {
+        self.status.set(self.old);
+    }
+}
 
-// // execute insert update delete
-// impl Session {
-//     fn batch_impl(&self, vec: &Vec<EntityInnerPointer>, op: Cascade) -> Result<(), Error> {
-//         vec.iter().fold(Ok(()), |result, rc| {
-//             if result.is_err() {
-//                 return result;
-//             }
-//             return self.execute_impl(rc.clone(), op.clone());
-//         })
-//     }
-//     fn execute_impl(&self, a_rc: EntityInnerPointer, op: Cascade) -> Result<(), Error> {
-//         if op == Cascade::NULL {
-//             return Ok(());
-//         }
-//         {
-//             // 一上来就设为持久态
-//             a_rc.borrow_mut().set_session(self.clone());
-//             // 本次事务内不会再操作该对象了
-//             a_rc.borrow_mut().cascade_null();
-//         }
-//         try!(self.execute_pointer(a_rc.clone(), op.clone()));
-//         try!(self.execute_self(a_rc.clone(), op.clone()));
-//         try!(self.execute_one_one(a_rc.clone(), op.clone()));
-//         try!(self.execute_one_many(a_rc.clone(), op.clone()));
-//         try!(self.execute_many_many(a_rc.clone(), op.clone()));
-//         try!(self.execute_middle(a_rc.clone(), op.clone()));
-//         Ok(())
-//     }
-//     fn execute_pointer(&self, a_rc: EntityInnerPointer, op: Cascade) -> Result<(), Error> {
-//         // pointer
-//         let meta = a_rc.borrow().meta;
-//         let pointer_map = a_rc.borrow().pointer_map.clone();
-//         let mut pointer_fields = Vec::new();
-//         for field_meta in meta.get_pointer_fields() {
-//             let field = field_meta.get_field_name();
-//             let b_rc = pointer_map.get(&field);
-//             if b_rc.is_none() {
-//                 continue;
-//             }
-//             let b_rc = b_rc.unwrap();
-//             if b_rc.is_none() {
-//                 continue;
-//             }
-//             let b_rc = b_rc.as_ref().unwrap();
-//             let cascade = Self::calc_cascade(a_rc.clone(), b_rc.clone(), &field, op.clone());
-//             if cascade == Cascade::NULL {
-//                 continue;
-//             }
-//             pointer_fields.push((field.to_string(), b_rc.clone()));
-//         }
-//         let res = try!(self.each_execute_refer(a_rc.clone(), &pointer_fields, op.clone()));
-//         // 更新关系
-//         for (field, b_rc) in pointer_fields {
-//             a_rc.borrow_mut().set_pointer(&field, Some(b_rc));
-//         }
-//         Ok(res)
-//     }
-//     fn execute_one_one(&self, a_rc: EntityInnerPointer, op: Cascade) -> Result<(), Error> {
-//         let meta = a_rc.borrow().meta;
-//         let one_one_map = a_rc.borrow().one_one_map.clone();
-//         let mut one_one_fields = Vec::new();
-//         for field_meta in meta.get_one_one_fields() {
-//             let field = field_meta.get_field_name();
-//             let b_rc = one_one_map.get(&field);
-//             if b_rc.is_none() {
-//                 continue;
-//             }
-//             let b_rc = b_rc.unwrap();
-//             if b_rc.is_none() {
-//                 continue;
-//             }
-//             let b_rc = b_rc.as_ref().unwrap();
+impl Session {
+    fn ensure_not_closed(&self) -> Result<(), Error> {
+        if self.status() == SessionStatus::Closed {
+            let msg = "Session Is Closed".to_string();
+            return Err(Error::IoError(io::Error::new(io::ErrorKind::NotConnected, msg)));
+        }
+        Ok(())
+    }
+    fn guard<F, R>(&self, status: SessionStatus, f: F) -> Result<R, Error>
+        where F: FnOnce() -> Result<R, Error>
+    {
+        try!(self.ensure_not_closed());
+        // f中panic时也要恢复原来的状态
+        let _guard = StatusGuard {
+            status: self.status.clone(),
+            old: self.status(),
+        };
+        self.status.set(status);
+        f()
+    }
+    pub fn push_cache(&self, rc: EntityInnerPointer, op: Cascade) -> Result<(), Error> {
+        try!(self.ensure_not_closed());
+        rc.borrow_mut().cascade = Some(op);
+        let addr = rc.borrow().get_addr();
+        let exists = self.cache.borrow().iter().any(|item| item.borrow().get_addr() == addr);
+        if !exists {
+            self.cache.borrow_mut().push(rc);
+        }
+        Ok(())
+    }
+    pub fn query_inner(&self, select: &SelectImpl) -> Result<Vec<EntityInnerPointer>, Error> {
+        let res = self.guard(SessionStatus::Select, || {
+            let mut conn = self.conn.borrow_mut();
+            let mut identity = self.identity.borrow_mut();
+            select.query_inner_map(conn.deref_mut(), identity.deref_mut())
+                .map(|mut vec| vec.remove(0))
+        });
+        self.attach();
+        res
+    }
+    // 新加载的对象设为持久态
+    fn attach(&self) {
+        for rc in self.identity.borrow().values() {
+            if rc.borrow().session.is_none() {
+                rc.borrow_mut().set_session(self);
+            }
+        }
+    }
+    fn flush_cache<C>(&self, conn: &mut C) -> Result<u64, Error>
+        where C: GenericConnection
+    {
+        let cache = self.cache.borrow().clone();
+        let pick = |op: Cascade| {
+            cache.iter()
+                .filter(|rc| rc.borrow().cascade == Some(op))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        let inserts = sort_by_dependency(pick(Cascade::Insert));
+        let mut updates = pick(Cascade::Update);
+        // 托管对象上有修改的，默认进行UPDATE
+        let mut dirties = self.identity
+            .borrow()
+            .values()
+            .filter(|rc| rc.borrow().cascade.is_none() && rc.borrow().is_dirty())
+            .cloned()
+            .collect::<Vec<_>>();
+        updates.append(&mut dirties);
+        // 删除的顺序与插入相反
+        let mut deletes = sort_by_dependency(pick(Cascade::Delete));
+        deletes.reverse();
 
-//             let cascade = Self::calc_cascade(a_rc.clone(), b_rc.clone(), &field, op.clone());
-//             if cascade == Cascade::NULL {
-//                 continue;
-//             }
-//             // 这里set是为了更新关系id
-//             a_rc.borrow_mut().set_one_one(&field, Some(b_rc.clone()));
-//             one_one_fields.push((field.to_string(), b_rc.clone()));
-//         }
-//         self.each_execute_refer(a_rc.clone(), &one_one_fields, op.clone())
-//     }
-//     fn execute_one_many(&self, a_rc: EntityInnerPointer, op: Cascade) -> Result<(), Error> {
-//         let meta = a_rc.borrow().meta;
-//         let one_many_map = a_rc.borrow().one_many_map.clone();
-//         let mut one_many_fields = Vec::new();
-//         for field_meta in meta.get_one_many_fields() {
-//             let field = field_meta.get_field_name();
-//             let vec_opt = one_many_map.get(&field);
-//             if vec_opt.is_none() {
-//                 continue;
-//             }
-//             let vec = vec_opt.unwrap();
-//             for b_rc in vec.iter() {
-//                 let cascade = Self::calc_cascade(a_rc.clone(), b_rc.clone(), &field, op.clone());
-//                 if cascade == Cascade::NULL {
-//                     continue;
-//                 }
-//                 one_many_fields.push((field.to_string(), b_rc.clone()));
-//             }
-//             // 这里set是为了更新关系id
-//             a_rc.borrow_mut().set_one_many(&field, vec.clone());
-//         }
-//         self.each_execute_refer(a_rc.clone(), &one_many_fields, op.clone())
-//     }
-//     fn execute_many_many(&self, a_rc: EntityInnerPointer, op: Cascade) -> Result<(), Error> {
-//         let meta = a_rc.borrow().meta;
-//         let many_many_map = a_rc.borrow().many_many_map.clone();
-//         let mut many_many_fields = Vec::new();
-//         // 先做对象表
-//         for field_meta in meta.get_many_many_fields() {
-//             let field = field_meta.get_field_name();
-//             let vec_opt = many_many_map.get(&field);
-//             if vec_opt.is_none() {
-//                 continue;
-//             }
-//             let vec = vec_opt.unwrap();
-//             for &(_, ref b_rc) in vec.iter() {
-//                 let cascade = Self::calc_cascade(a_rc.clone(), b_rc.clone(), &field, op.clone());
-//                 if cascade == Cascade::NULL {
-//                     continue;
-//                 }
-//                 many_many_fields.push((field.to_string(), b_rc.clone()));
-//             }
-//         }
-//         try!(self.each_execute_refer(a_rc.clone(), &many_many_fields, op.clone()));
-//         // 这里set是为了更新关系id
-//         for (field, vec) in many_many_map.into_iter() {
-//             let vec = vec.into_iter().map(|(_, b_rc)| b_rc).collect::<Vec<_>>();
-//             a_rc.borrow_mut().set_many_many(&field, vec);
-//         }
-//         Ok(())
-//     }
-//     fn execute_middle(&self, a_rc: EntityInnerPointer, op: Cascade) -> Result<(), Error> {
-//         let meta = a_rc.borrow().meta;
-//         let many_many_map = a_rc.borrow().many_many_map.clone();
-//         let mut many_many_fields = Vec::new();
-//         // 后做中间表
-//         for field_meta in meta.get_many_many_fields() {
-//             let field = field_meta.get_field_name();
-//             let vec_opt = many_many_map.get(&field);
-//             if vec_opt.is_none() {
-//                 continue;
-//             }
-//             let vec = vec_opt.unwrap();
-//             for &(ref m_rc, _) in vec.iter() {
-//                 if m_rc.is_none() {
-//                     continue;
-//                 }
-//                 let m_rc = m_rc.as_ref().unwrap();
-//                 let cascade = Self::calc_cascade(a_rc.clone(), m_rc.clone(), &field, op.clone());
-//                 if cascade == Cascade::NULL {
-//                     continue;
-//                 }
-//                 many_many_fields.push((field.to_string(), m_rc.clone()));
-//             }
-//         }
-//         self.each_execute_refer(a_rc.clone(), &many_many_fields, op.clone())
-//     }
-//     fn execute_self(&self, a_rc: EntityInnerPointer, op: Cascade) -> Result<(), Error> {
-//         match op {
-//             Cascade::Insert => a_rc.borrow_mut().do_insert(self.conn.borrow_mut().deref_mut()),
-//             Cascade::Update => a_rc.borrow_mut().do_update(self.conn.borrow_mut().deref_mut()),
-//             Cascade::Delete => a_rc.borrow_mut().do_delete(self.conn.borrow_mut().deref_mut()),
-//             Cascade::NULL => Ok(()),
-//         }
-//     }
-//     fn each_execute_refer(&self,
-//                           a_rc: EntityInnerPointer,
-//                           vec: &Vec<(String, EntityInnerPointer)>,
-//                           op: Cascade)
-//                           -> Result<(), Error> {
-//         for &(ref field, ref b_rc) in vec.iter() {
-//             try!(self.execute_refer(a_rc.clone(), b_rc.clone(), field, op.clone()));
-//         }
-//         Ok(())
-//     }
-//     fn execute_refer(&self,
-//                      a_rc: EntityInnerPointer,
-//                      b_rc: EntityInnerPointer,
-//                      field: &str,
-//                      op: Cascade)
-//                      -> Result<(), Error> {
-//         let cascade = Self::calc_cascade(a_rc.clone(), b_rc.clone(), field, op);
-//         self.execute_impl(b_rc, cascade)
-//     }
-//     fn calc_cascade(a_rc: EntityInnerPointer,
-//                     b_rc: EntityInnerPointer,
-//                     field: &str,
-//                     op: Cascade)
-//                     -> Cascade {
-//         // 1. 对象动态级联
-//         // 2. 配置动态级联
-//         // 3. 配置静态级联
-//         let a = a_rc.borrow();
-//         let a_b_meta = a.meta.field_map.get(field).unwrap();
-//         if b_rc.borrow().cascade.is_some() {
-//             return b_rc.borrow().cascade.unwrap().clone();
-//         } else if a_b_meta.get_refer_rt_cascade().is_some() {
-//             return a_b_meta.get_refer_rt_cascade().clone().unwrap();
-//         } else if a_b_meta.has_cascade_insert() && op == Cascade::Insert {
-//             return Cascade::Insert;
-//         } else if a_b_meta.has_cascade_update() && op == Cascade::Update {
-//             return Cascade::Update;
-//         } else if a_b_meta.has_cascade_delete() && op == Cascade::Delete {
-//             return Cascade::Delete;
-//         } else {
-//             return Cascade::NULL;
-//         }
-//     }
-// }
+        // 同一个对象在一次flush中只处理一次
+        let mut set = ExecuteSet::new();
+        let mut ret = 0;
+        let execute = Execute::insert();
+        for rc in inserts.iter() {
+            sync_pointer_ids(rc);
+            ret += try!(self.guard(SessionStatus::Insert,
+                                   || execute.execute_in(conn, rc.clone(), &mut set)));
+            sync_child_ids(rc);
+        }
+        let execute = Execute::update();
+        for rc in updates.iter() {
+            ret += try!(self.guard(SessionStatus::Update,
+                                   || execute.execute_in(conn, rc.clone(), &mut set)));
+        }
+        let execute = Execute::delete();
+        for rc in deletes.iter() {
+            ret += try!(self.guard(SessionStatus::Delete,
+                                   || execute.execute_in(conn, rc.clone(), &mut set)));
+        }
+        Ok(ret)
+    }
+    // 事务提交之后才修改缓存和identity
+    fn finish_flush(&self) {
+        let cache = mem::replace(self.cache.borrow_mut().deref_mut(), Vec::new());
+        for rc in cache.iter() {
+            // 插入的对象变为持久态，删除的对象变为临时态
+            if rc.borrow().cascade == Some(Cascade::Insert) {
+                let key = identity_key(rc.borrow().meta, rc.borrow().get_id_u64().unwrap());
+                self.identity.borrow_mut().insert(key, rc.clone());
+            }
+            let mut inner = rc.borrow_mut();
+            match inner.cascade {
+                Some(Cascade::Insert) => inner.set_session(self),
+                Some(Cascade::Delete) => inner.clear_session(),
+                _ => {}
+            }
+            // 重置动态级联标记
+            inner.cascade = None;
+        }
+        // 删除的对象(包括级联删除的)id已经清空, 从identity中去掉, 变为临时态
+        let keys = self.identity
+            .borrow()
+            .iter()
+            .filter(|&(_, rc)| rc.borrow().get_id_u64().is_none())
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in keys.iter() {
+            if let Some(rc) = self.identity.borrow_mut().remove(key) {
+                rc.borrow_mut().clear_session();
+            }
+        }
+    }
+    // 从缓存和identity出发, 记录所有能访问到的对象
+    fn snapshot(&self) -> Snapshot {
+        let mut rcs = self.cache.borrow().clone();
+        rcs.extend(self.identity.borrow().values().cloned());
+        let mut visited = HashSet::new();
+        let mut ret = Vec::new();
+        while let Some(rc) = rcs.pop() {
+            let inner = rc.borrow();
+            if visited.contains(&inner.get_addr()) {
+                continue;
+            }
+            visited.insert(inner.get_addr());
+            for value in inner.field_map.values() {
+                match value {
+                    &FieldValue::Entity(Some(ref b_rc)) => rcs.push(b_rc.clone()),
+                    &FieldValue::Vec(ref vec) => rcs.extend(vec.iter().cloned()),
+                    _ => {}
+                }
+            }
+            ret.push((rc.clone(),
+                      inner.field_map.clone(),
+                      inner.origin_map.clone(),
+                      inner.dirty_set.clone()));
+        }
+        ret
+    }
+}
 
-// // select
-// impl Session {
-//     fn select_impl(&self,
-//                    meta: &'static EntityMeta,
-//                    orm_meta: &'static OrmMeta,
-//                    cond: &Cond)
-//                    -> Result<Vec<EntityInnerPointer>, Error> {
-//         let table_alias = &meta.table_name;
-//         let mut tables = Vec::new();
-//         let mut fields = Vec::new();
-//         Self::gen_sql(&meta.entity_name,
-//                       &table_alias,
-//                       orm_meta,
-//                       &mut tables,
-//                       &mut fields);
+// flush前对象的状态: 字段(包括id和外键), 原始值和脏字段
+type Snapshot = Vec<(EntityInnerPointer,
+                     HashMap<String, FieldValue>,
+                     HashMap<String, Option<Value>>,
+                     HashSet<String>)>;
 
-//         let fields = fields.into_iter()
-//             .map(|vec| vec.iter().map(|line| format!("\t{}", line)).collect::<Vec<_>>().join(",\n"))
-//             .collect::<Vec<_>>()
-//             .join(",\n\n");
-//         tables.insert(0, format!("{} AS {}", &meta.table_name, table_alias));
-//         let tables = tables.iter().map(|line| format!("\t{}", line)).collect::<Vec<_>>().join("\n");
-//         // let cond = format!("\t{}.id = {}", &meta.table_name, id);
-//         let sql = format!("SELECT \n{} \nFROM \n{} \nWHERE \n\t{}",
-//                           fields,
-//                           tables,
-//                           cond.to_sql(table_alias));
-//         log!("{}", sql);
-//         log!("\t{:?}", cond.to_params(table_alias));
+fn restore(snapshot: Snapshot) {
+    for (rc, field_map, origin_map, dirty_set) in snapshot.into_iter() {
+        let mut inner = rc.borrow_mut();
+        inner.field_map = field_map;
+        inner.origin_map = origin_map;
+        inner.dirty_set = dirty_set;
+    }
+}
 
-//         let mut conn = self.conn.borrow_mut();
-//         let query_result = try!(conn.prep_exec(sql, cond.to_params(table_alias)));
+// pointer指向的对象排在前面，one_one/one_many的子对象排在后面
+fn sort_by_dependency(vec: Vec<EntityInnerPointer>) -> Vec<EntityInnerPointer> {
+    let addrs = vec.iter().map(|rc| rc.borrow().get_addr()).collect::<HashSet<_>>();
+    let mut preds: HashMap<u64, Vec<EntityInnerPointer>> = HashMap::new();
+    for a_rc in vec.iter() {
+        let a = a_rc.borrow();
+        let a_addr = a.get_addr();
+        for a_b_meta in a.meta.get_pointer_fields() {
+            let b_rc = a.field_map.get(&a_b_meta.get_field_name()).and_then(|v| v.as_entity());
+            if let Some(b_rc) = b_rc {
+                let b_addr = b_rc.borrow().get_addr();
+                if addrs.contains(&b_addr) {
+                    preds.entry(a_addr).or_insert(Vec::new()).push(b_rc.clone());
+                }
+            }
+        }
+        let mut children = Vec::new();
+        for a_b_meta in a.meta.get_one_one_fields() {
+            let b_rc = a.field_map.get(&a_b_meta.get_field_name()).and_then(|v| v.as_entity());
+            children.extend(b_rc);
+        }
+        for a_b_meta in a.meta.get_one_many_fields() {
+            let vec = a.field_map.get(&a_b_meta.get_field_name()).map(|v| v.as_vec());
+            children.extend(vec.unwrap_or(Vec::new()));
+        }
+        for b_rc in children {
+            let b_addr = b_rc.borrow().get_addr();
+            if addrs.contains(&b_addr) {
+                preds.entry(b_addr).or_insert(Vec::new()).push(a_rc.clone());
+            }
+        }
+    }
+    let mut visited = HashSet::new();
+    let mut ret = Vec::new();
+    for rc in vec.iter() {
+        visit_dependency(rc, &preds, &mut visited, &mut ret);
+    }
+    ret
+}
 
-//         let mut map: HashMap<String, EntityInnerPointer> = HashMap::new();
-//         let mut vec = Vec::new();
-//         for row in query_result {
-//             let mut row = try!(row);
-//             match self.take_entity(&mut row, table_alias, meta, orm_meta, &mut map) {
-//                 Some(rc) => vec.push(rc), 
-//                 None => {}
-//             }
-//         }
-//         let vec =
-//             vec.into_iter().unique_by(|rc| rc.borrow().get_id_u64().unwrap()).collect::<Vec<_>>();
-//         Ok(vec)
-//     }
-//     fn take_entity(&self,
-//                    mut row: &mut Row,
-//                    table_alias: &str,
-//                    meta: &'static EntityMeta,
-//                    orm_meta: &'static OrmMeta,
-//                    mut map: &mut HashMap<String, EntityInnerPointer>)
-//                    -> Option<EntityInnerPointer> {
-//         // 关系是空的，这样才能判断出lazy的情况
-//         let mut a = EntityInner::new(meta, orm_meta);
-//         // 一上来就设为持久态
-//         a.set_session(self.clone());
-//         a.set_values(&mut row, &table_alias);
-//         let id = a.get_id_u64();
-//         if id.is_none() {
-//             return None;
-//         }
-//         let id = id.unwrap();
-//         let key = format!("{}_{}", table_alias, id);
-//         let a_rc = match map.get(&key) {
-//             Some(rc) => rc.clone(),
-//             None => Rc::new(RefCell::new(a)),
-//         };
-//         map.insert(key, a_rc.clone());
+fn visit_dependency(rc: &EntityInnerPointer,
+                    preds: &HashMap<u64, Vec<EntityInnerPointer>>,
+                    visited: &mut HashSet<u64>,
+                    ret: &mut Vec<EntityInnerPointer>) {
+    let addr = rc.borrow().get_addr();
+    if visited.contains(&addr) {
+        return;
+    }
+    visited.insert(addr);
+    if let Some(vec) = preds.get(&addr) {
+        for pred in vec.iter() {
+            visit_dependency(pred, preds, visited, ret);
+        }
+    }
+    ret.push(rc.clone());
+}
 
-//         self.take_entity_pointer(a_rc.clone(), &mut row, table_alias, &mut map);
-//         self.take_entity_one_one(a_rc.clone(), &mut row, table_alias, &mut map);
-//         self.take_entity_one_many(a_rc.clone(), &mut row, table_alias, &mut map);
-//         self.take_entity_many_many(a_rc.clone(), &mut row, table_alias, &mut map);
-//         Some(a_rc)
-//     }
-//     fn take_entity_pointer(&self,
-//                            a_rc: EntityInnerPointer,
-//                            mut row: &mut Row,
-//                            table_alias: &str,
-//                            mut map: &mut HashMap<String, EntityInnerPointer>) {
-//         let mut a = a_rc.borrow_mut();
-//         for a_b_meta in a.meta.get_pointer_fields() {
-//             if !a_b_meta.is_fetch_eager() {
-//                 continue;
-//             }
-//             let b_entity = a_b_meta.get_refer_entity();
-//             let a_b_field = a_b_meta.get_field_name();
-//             let b_meta = a.orm_meta.entity_map.get(&b_entity).unwrap();
-//             let b_table_alias = format!("{}_{}", table_alias, a_b_field);
-//             match self.take_entity(&mut row, &b_table_alias, &b_meta, &a.orm_meta, &mut map) {
-//                 Some(b_rc) => a.set_pointer(&a_b_field, Some(b_rc)),
-//                 None => a.set_pointer(&a_b_field, None),
-//             }
-//         }
-//     }
-//     fn take_entity_one_one(&self,
-//                            a_rc: EntityInnerPointer,
-//                            mut row: &mut Row,
-//                            table_alias: &str,
-//                            mut map: &mut HashMap<String, EntityInnerPointer>) {
-//         let mut a = a_rc.borrow_mut();
-//         for a_b_meta in a.meta.get_one_one_fields() {
-//             if !a_b_meta.is_fetch_eager() {
-//                 continue;
-//             }
-//             let b_entity = a_b_meta.get_refer_entity();
-//             let a_b_field = a_b_meta.get_field_name();
-//             let b_meta = a.orm_meta.entity_map.get(&b_entity).unwrap();
-//             let b_table_alias = format!("{}_{}", table_alias, a_b_field);
-//             match self.take_entity(&mut row, &b_table_alias, &b_meta, &a.orm_meta, &mut map) {
-//                 Some(b_rc) => a.set_one_one(&a_b_field, Some(b_rc)),
-//                 None => a.set_one_one(&a_b_field, None),
-//             }
-//         }
-//     }
-//     fn take_entity_one_many(&self,
-//                             a_rc: EntityInnerPointer,
-//                             mut row: &mut Row,
-//                             table_alias: &str,
-//                             mut map: &mut HashMap<String, EntityInnerPointer>) {
-//         let mut a = a_rc.borrow_mut();
-//         for a_b_meta in a.meta.get_one_many_fields() {
-//             if !a_b_meta.is_fetch_eager() {
-//                 continue;
-//             }
-//             let b_entity = a_b_meta.get_refer_entity();
-//             let a_b_field = a_b_meta.get_field_name();
-//             let b_meta = a.orm_meta.entity_map.get(&b_entity).unwrap();
-//             let b_table_alias = format!("{}_{}", table_alias, a_b_field);
-//             match self.take_entity(&mut row, &b_table_alias, &b_meta, &a.orm_meta, &mut map) {
-//                 Some(b_rc) => {
-//                     let key = format!("ONE_MANY@{}_{}",
-//                                       b_table_alias,
-//                                       b_rc.borrow().get_id_u64().unwrap());
-//                     if !map.contains_key(&key) {
-//                         a.push_one_many(&a_b_field, b_rc.clone());
-//                     }
-//                     map.entry(key).or_insert(b_rc);
-//                 }
-//                 None => {}
-//             }
-//         }
-//     }
-//     fn take_entity_many_many(&self,
-//                              a_rc: EntityInnerPointer,
-//                              mut row: &mut Row,
-//                              table_alias: &str,
-//                              mut map: &mut HashMap<String, EntityInnerPointer>) {
-//         let mut a = a_rc.borrow_mut();
-//         for a_b_meta in a.meta.get_many_many_fields() {
-//             if !a_b_meta.is_fetch_eager() {
-//                 continue;
-//             }
-//             let b_entity = a_b_meta.get_refer_entity();
-//             let mid_entity = a_b_meta.get_many_many_middle_entity();
-//             let a_b_field = a_b_meta.get_field_name();
-//             let b_meta = a.orm_meta.entity_map.get(&b_entity).unwrap();
-//             let mid_meta = a.orm_meta.entity_map.get(&mid_entity).unwrap();
-//             let b_table_alias = format!("{}_{}", table_alias, a_b_field);
-//             let mid_table_alias = format!("{}__{}", table_alias, a_b_field);
-//             match self.take_entity(&mut row, &b_table_alias, &b_meta, &a.orm_meta, &mut map) {
-//                 Some(b_rc) => {
-//                     let key = format!("MANY_MANY@{}_{}",
-//                                       b_table_alias,
-//                                       b_rc.borrow().get_id_u64().unwrap());
-//                     if !map.contains_key(&key) {
-//                         let mid_rc = self.take_entity(&mut row,
-//                                          &mid_table_alias,
-//                                          &mid_meta,
-//                                          &a.orm_meta,
-//                                          &mut map)
-//                             .unwrap();
-//                         a.push_many_many(&a_b_field, (mid_rc, b_rc.clone()));
-//                     }
-//                     map.entry(key).or_insert(b_rc);
-//                 }
-//                 None => {}
-//             }
-//         }
-//     }
-//     fn gen_sql(entity: &str,
-//                table_alias: &str,
-//                orm_meta: &'static OrmMeta,
-//                mut tables: &mut Vec<String>,
-//                mut columns: &mut Vec<Vec<String>>) {
-//         let meta = orm_meta.entity_map.get(entity).unwrap();
-//         let self_columns = Self::gen_sql_columns(meta, table_alias);
-//         columns.push(self_columns);
+// a.b_id = b.id
+fn sync_pointer_ids(a_rc: &EntityInnerPointer) {
+    let meta = a_rc.borrow().meta;
+    for a_b_meta in meta.get_pointer_fields() {
+        let b_rc = a_rc.borrow()
+            .field_map
+            .get(&a_b_meta.get_field_name())
+            .and_then(|v| v.as_entity());
+        if let Some(b_rc) = b_rc {
+            let (left, right) = a_b_meta.get_refer_lr();
+            let b_id = b_rc.borrow().field_map.get(&right).map(FieldValue::as_value);
+            if let Some(b_id) = b_id {
+                a_rc.borrow_mut().set_field_value(&left, b_id);
+            }
+        }
+    }
+}
 
-//         Self::gen_sql_pointer(table_alias, meta, orm_meta, tables, columns);
-//         Self::gen_sql_one_one(table_alias, meta, orm_meta, tables, columns);
-//         Self::gen_sql_one_many(table_alias, meta, orm_meta, tables, columns);
-//         Self::gen_sql_many_many(table_alias, meta, orm_meta, tables, columns);
-//     }
-//     fn gen_sql_pointer(table_alias: &str,
-//                        meta: &'static EntityMeta,
-//                        orm_meta: &'static OrmMeta,
-//                        mut tables: &mut Vec<String>,
-//                        mut columns: &mut Vec<Vec<String>>) {
-//         for a_b_meta in meta.get_pointer_fields().into_iter() {
-//             if !a_b_meta.is_fetch_eager() {
-//                 continue;
-//             }
-//             // a join b on a.b_id = b.id
-//             let a_b_field = a_b_meta.get_field_name();
-//             let b_entity = a_b_meta.get_refer_entity();
-//             let b_meta = orm_meta.entity_map.get(&b_entity).unwrap();
-//             let b_table_name = &b_meta.table_name;
-//             let a_b_id_field = a_b_meta.get_pointer_id();
-//             let a_b_id_meta = meta.field_map.get(&a_b_id_field).unwrap();
-//             let a_b_id_column = a_b_id_meta.get_column_name();
-//             let b_table_alias = format!("{}_{}", &table_alias, &a_b_field);
-//             let join_table = format!("LEFT JOIN {} AS {} ON {}.{} = {}.id",
-//                                      &b_table_name,
-//                                      &b_table_alias,
-//                                      &table_alias,
-//                                      &a_b_id_column,
-//                                      &b_table_alias);
-//             tables.push(join_table);
-//             Self::gen_sql(&b_entity,
-//                           &b_table_alias,
-//                           orm_meta,
-//                           &mut tables,
-//                           &mut columns);
-//         }
-//     }
-//     fn gen_sql_one_one(table_alias: &str,
-//                        meta: &'static EntityMeta,
-//                        orm_meta: &'static OrmMeta,
-//                        mut tables: &mut Vec<String>,
-//                        mut columns: &mut Vec<Vec<String>>) {
-//         for a_b_meta in meta.get_one_one_fields().into_iter() {
-//             if !a_b_meta.is_fetch_eager() {
-//                 continue;
-//             }
-//             // a join b on a.id = b.a_id
-//             let a_b_field = a_b_meta.get_field_name();
-//             let b_entity = a_b_meta.get_refer_entity();
-//             let b_meta = orm_meta.entity_map.get(&b_entity).unwrap();
-//             let b_table_name = &b_meta.table_name;
-//             let b_a_id_field = a_b_meta.get_one_one_id();
-//             let b_a_id_meta = b_meta.field_map.get(&b_a_id_field).unwrap();
-//             let b_a_id_column = b_a_id_meta.get_column_name();
-//             let b_table_alias = format!("{}_{}", &table_alias, &a_b_field);
-//             let join_table = format!("LEFT JOIN {} AS {} ON {}.id = {}.{}",
-//                                      &b_table_name,
-//                                      &b_table_alias,
-//                                      &table_alias,
-//                                      &b_table_alias,
-//                                      &b_a_id_column);
-//             tables.push(join_table);
-//             Self::gen_sql(&b_entity,
-//                           &b_table_alias,
-//                           orm_meta,
-//                           &mut tables,
-//                           &mut columns);
-//         }
-//     }
-//     fn gen_sql_one_many(table_alias: &str,
-//                         meta: &'static EntityMeta,
-//                         orm_meta: &'static OrmMeta,
-//                         mut tables: &mut Vec<String>,
-//                         mut columns: &mut Vec<Vec<String>>) {
-//         for a_b_meta in meta.get_one_many_fields().into_iter() {
-//             if !a_b_meta.is_fetch_eager() {
-//                 continue;
-//             }
-//             // a join b on a.id = b.a_id
-//             let a_b_field = a_b_meta.get_field_name();
-//             let b_entity = a_b_meta.get_refer_entity();
-//             let b_meta = orm_meta.entity_map.get(&b_entity).unwrap();
-//             let b_table_name = &b_meta.table_name;
-//             let b_a_id_field = a_b_meta.get_one_many_id();
-//             let b_a_id_meta = b_meta.field_map.get(&b_a_id_field).unwrap();
-//             let b_a_id_column = b_a_id_meta.get_column_name();
-//             let b_table_alias = format!("{}_{}", &table_alias, &a_b_field);
-//             let join_table = format!("LEFT JOIN {} AS {} ON {}.id = {}.{}",
-//                                      &b_table_name,
-//                                      &b_table_alias,
-//                                      &table_alias,
-//                                      &b_table_alias,
-//                                      &b_a_id_column);
-//             tables.push(join_table);
-//             Self::gen_sql(&b_entity,
-//                           &b_table_alias,
-//                           orm_meta,
-//                           &mut tables,
-//                           &mut columns);
-//         }
-//     }
-//     fn gen_sql_many_many(table_alias: &str,
-//                          meta: &'static EntityMeta,
-//                          orm_meta: &'static OrmMeta,
-//                          mut tables: &mut Vec<String>,
-//                          mut columns: &mut Vec<Vec<String>>) {
-//         for a_b_meta in meta.get_many_many_fields().into_iter() {
-//             if !a_b_meta.is_fetch_eager() {
-//                 continue;
-//             }
-//             // a join a_b on a.id = a_b.a_id join b on a_b.b_id = b.id
-//             let a_b_field = a_b_meta.get_field_name();
-//             let b_entity = a_b_meta.get_refer_entity();
-//             let mid_entity = a_b_meta.get_many_many_middle_entity();
-//             let b_meta = orm_meta.entity_map.get(&b_entity).unwrap();
-//             let mid_meta = orm_meta.entity_map.get(&mid_entity).unwrap();
-//             let b_table_name = &b_meta.table_name;
-//             let mid_table_name = &mid_meta.table_name;
-//             let mid_a_id_field = a_b_meta.get_many_many_id();
-//             let mid_b_id_field = a_b_meta.get_many_many_refer_id();
-//             let mid_a_id_meta = mid_meta.field_map.get(&mid_a_id_field).unwrap();
-//             let mid_b_id_meta = mid_meta.field_map.get(&mid_b_id_field).unwrap();
-//             let mid_a_id_column = mid_a_id_meta.get_column_name();
-//             let mid_b_id_column = mid_b_id_meta.get_column_name();
-//             let mid_table_alias = format!("{}__{}", &table_alias, &a_b_field);
-//             let b_table_alias = format!("{}_{}", &table_alias, &a_b_field);
-//             let join_mid = format!("LEFT JOIN {} AS {} ON {}.id = {}.{}",
-//                                    &mid_table_name,
-//                                    &mid_table_alias,
-//                                    &table_alias,
-//                                    &mid_table_alias,
-//                                    &mid_a_id_column);
-//             let join_b = format!("LEFT JOIN {} AS {} ON {}.{} = {}.id",
-//                                  &b_table_name,
-//                                  &b_table_alias,
-//                                  &mid_table_alias,
-//                                  &mid_b_id_column,
-//                                  &b_table_alias);
-//             tables.push(join_mid);
-//             Self::gen_sql(&mid_entity,
-//                           &mid_table_alias,
-//                           orm_meta,
-//                           &mut tables,
-//                           &mut columns);
-//             tables.push(join_b);
-//             Self::gen_sql(&b_entity,
-//                           &b_table_alias,
-//                           orm_meta,
-//                           &mut tables,
-//                           &mut columns);
-//         }
-//     }
-//     fn gen_sql_columns(meta: &'static EntityMeta, table_alias: &str) -> Vec<String> {
-//         meta.get_non_refer_fields()
-//             .iter()
-//             .map(|field_meta| {
-//                 let column_name = field_meta.get_column_name();
-//                 let field_name = field_meta.get_field_name();
-//                 format!("{}.{} as {}${}",
-//                         &table_alias,
-//                         &column_name,
-//                         &table_alias,
-//                         &field_name)
-//             })
-//             .collect()
-//     }
-// }
+// b.a_id = a.id
+fn sync_child_ids(a_rc: &EntityInnerPointer) {
+    let meta = a_rc.borrow().meta;
+    for a_b_meta in meta.get_one_one_fields().into_iter().chain(meta.get_one_many_fields()) {
+        let (left, right) = a_b_meta.get_refer_lr();
+        let a_id = a_rc.borrow().field_map.get(&left).map(FieldValue::as_value);
+        let children = match a_rc.borrow().field_map.get(&a_b_meta.get_field_name()) {
+            Some(&FieldValue::Entity(ref opt)) => opt.iter().cloned().collect::<Vec<_>>(),
+            Some(&FieldValue::Vec(ref vec)) => vec.clone(),
+            _ => Vec::new(),
+        };
+        if let Some(a_id) = a_id {
+            for b_rc in children {
+                b_rc.borrow_mut().set_field_value(&right, a_id.clone());
+            }
+        }
+    }
+}
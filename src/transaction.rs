@@ -0,0 +1,118 @@
+#[macro_use]
+use macros;
+
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::cell::RefMut;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+
+use mysql;
+use mysql::Error;
+use mysql::conn::GenericConnection;
+
+use meta::OrmMeta;
+use entity::Entity;
+use insert::Insert;
+use select::Select;
+use select::Page;
+use execute::Execute;
+
+pub struct Transaction {
+    conn: RefCell<mysql::Transaction<'static>>,
+    orm_meta: &'static OrmMeta,
+    savepoint: Cell<u64>,
+}
+
+impl Transaction {
+    pub fn new(conn: mysql::Transaction<'static>, orm_meta: &'static OrmMeta) -> Self {
+        Transaction {
+            conn: RefCell::new(conn),
+            orm_meta: orm_meta,
+            savepoint: Cell::new(0),
+        }
+    }
+    // Ok时提交，Err或panic时回滚
+    pub fn guard<F, R>(self, f: F) -> Result<R, Error>
+        where F: FnOnce(&Transaction) -> Result<R, Error>
+    {
+        let res = panic::catch_unwind(AssertUnwindSafe(|| f(&self)));
+        let conn = self.conn.into_inner();
+        match res {
+            Ok(Ok(ret)) => {
+                log!("COMMIT");
+                conn.commit().map(|_| ret)
+            }
+            Ok(Err(err)) => {
+                log!("ROLLBACK");
+                conn.rollback().and_then(|_| Err(err))
+            }
+            Err(cause) => {
+                log!("ROLLBACK");
+                let _ = conn.rollback();
+                panic::resume_unwind(cause)
+            }
+        }
+    }
+    // 嵌套事务，使用savepoint实现，只回滚自己内部的操作
+    pub fn transaction<F, R>(&self, f: F) -> Result<R, Error>
+        where F: FnOnce(&Transaction) -> Result<R, Error>
+    {
+        self.savepoint.set(self.savepoint.get() + 1);
+        let name = format!("sp_{}", self.savepoint.get());
+        try!(self.exec_sql(&format!("SAVEPOINT {}", name)));
+        let res = panic::catch_unwind(AssertUnwindSafe(|| f(self)));
+        match res {
+            Ok(Ok(ret)) => self.exec_sql(&format!("RELEASE SAVEPOINT {}", name)).map(|_| ret),
+            Ok(Err(err)) => {
+                self.exec_sql(&format!("ROLLBACK TO SAVEPOINT {}", name)).and_then(|_| Err(err))
+            }
+            Err(cause) => {
+                let _ = self.exec_sql(&format!("ROLLBACK TO SAVEPOINT {}", name));
+                panic::resume_unwind(cause)
+            }
+        }
+    }
+    fn exec_sql(&self, sql: &str) -> Result<(), Error> {
+        log!("{}", sql);
+        self.get_conn().query(sql).map(|_| ())
+    }
+    pub fn get_conn(&self) -> RefMut<mysql::Transaction<'static>> {
+        self.conn.borrow_mut()
+    }
+    pub fn orm_meta(&self) -> &'static OrmMeta {
+        self.orm_meta
+    }
+    pub fn insert<E>(&self, entity: &E) -> Result<u64, Error>
+        where E: Entity
+    {
+        let insert = Insert::default::<E>();
+        insert.execute(&mut *self.get_conn(), entity)
+    }
+    pub fn execute<E>(&self, execute: &Execute, entity: &E) -> Result<u64, Error>
+        where E: Entity
+    {
+        execute.execute(&mut *self.get_conn(), entity)
+    }
+    #[deprecated(note = "rows of joined entities are wrapped as E, use join2 instead")]
+    #[allow(deprecated)]
+    pub fn query_ex<E>(&self, select: &Select<E>) -> Result<Vec<Vec<E>>, Error>
+        where E: Entity
+    {
+        select.query_ex(&mut *self.get_conn())
+    }
+    pub fn query<E>(&self, select: &Select<E>) -> Result<Vec<E>, Error>
+        where E: Entity
+    {
+        select.query(&mut *self.get_conn())
+    }
+    pub fn page<E>(&self,
+                   select: &Select<E>,
+                   page_no: u64,
+                   page_size: u64)
+                   -> Result<Page<E>, Error>
+        where E: Entity
+    {
+        select.page(&mut *self.get_conn(), page_no, page_size)
+    }
+}